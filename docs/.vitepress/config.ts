@@ -16,7 +16,8 @@ export default defineConfig({
         items: [
           { text: 'Getting Started', link: '/guide/getting-started' },
           { text: 'Recommended Platforms', link: '/guide/recommended-platforms' },
-          { text: 'Architecture', link: '/guide/architecture' }
+          { text: 'Architecture', link: '/guide/architecture' },
+          { text: 'Format Specification', link: '/guide/format' }
         ]
       },
       {
@@ -0,0 +1,467 @@
+use crate::metadata::{file_name, Metadata, Restore};
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+pub const MANIFEST_MAGIC: u64 = 0x4554_524E_4C41_5243; // "ETRNLARC" in hex
+const PREFIX_SIZE: usize = 12; // magic (u64) + manifest length (u32)
+
+/// Value of the `EXT_ARCHIVE` header extension: how the stream is laid out
+pub const ARCHIVE_FORMAT: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Relative and `/`-separated, starting with the name of the input it came from
+    pub path: String,
+    pub kind: EntryKind,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Everything in an archive, in stream order. Directories come before
+/// their contents, and file contents follow the manifest in the same order.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// The start of the stream: magic, manifest length and the manifest as JSON.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let json = serde_json::to_vec(self).context("Failed to serialize manifest")?;
+        let mut bytes = Vec::with_capacity(PREFIX_SIZE + json.len());
+        bytes.write_u64::<BigEndian>(MANIFEST_MAGIC)?;
+        bytes.write_u32::<BigEndian>(json.len().try_into().context("Manifest too large")?)?;
+        bytes.extend_from_slice(&json);
+        Ok(bytes)
+    }
+
+    /// Length of the manifest that follows, from the stream prefix.
+    fn length(mut prefix: &[u8]) -> Result<usize> {
+        if prefix.read_u64::<BigEndian>()? != MANIFEST_MAGIC {
+            return Err(anyhow::anyhow!("Invalid archive manifest magic number"));
+        }
+        Ok(prefix.read_u32::<BigEndian>()? as usize)
+    }
+
+    /// Reads the start of a stream of `stream_size` bytes up to the end of
+    /// the manifest. Returns the manifest and the bytes it was read from.
+    pub fn read(reader: &mut impl Read, stream_size: u64) -> Result<(Self, Vec<u8>)> {
+        let mut prefix = vec![0u8; PREFIX_SIZE];
+        reader.read_exact(&mut prefix).context("Archive stream ends before its manifest")?;
+        // The length comes from decoded data, so it is checked before anything is read by it
+        let length = Self::length(&prefix)?;
+        if (PREFIX_SIZE + length) as u64 > stream_size {
+            return Err(anyhow::anyhow!(
+                "Archive manifest claims {} bytes, more than the {} byte stream",
+                length,
+                stream_size
+            ));
+        }
+        reader.take(length as u64).read_to_end(&mut prefix)?;
+        if prefix.len() < PREFIX_SIZE + length {
+            return Err(anyhow::anyhow!("Archive stream ends inside its manifest"));
+        }
+        let manifest = serde_json::from_slice(&prefix[PREFIX_SIZE..]).context("Archive manifest is damaged")?;
+        Ok((manifest, prefix))
+    }
+
+    fn files(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().filter(|e| e.kind == EntryKind::File)
+    }
+
+    /// Size of the whole stream: manifest plus file contents
+    pub fn stream_size(&self) -> Result<u64> {
+        Ok(self.to_bytes()?.len() as u64 + self.files().map(|e| e.size).sum::<u64>())
+    }
+}
+
+/// The inputs of an archive: their manifest, and where each file is on disk.
+pub struct Archive {
+    pub manifest: Manifest,
+    sources: Vec<PathBuf>,
+}
+
+impl Archive {
+    /// Walks the inputs and hashes every file, recording extended attributes
+    /// too if `xattrs` is set. Symlinks and special files are skipped with a warning.
+    pub fn scan(inputs: &[String], xattrs: bool) -> Result<Self> {
+        let mut archive = Self { manifest: Manifest::default(), sources: Vec::new() };
+        let mut names = Vec::new();
+        for input in inputs {
+            let name = file_name(input)?;
+            if names.contains(&name) {
+                return Err(anyhow::anyhow!("Two inputs are named {}, they would overwrite each other", name));
+            }
+            archive.add(Path::new(input), name.clone(), xattrs)?;
+            names.push(name);
+        }
+        Ok(archive)
+    }
+
+    fn add(&mut self, path: &Path, name: String, xattrs: bool) -> Result<()> {
+        let file_type = std::fs::symlink_metadata(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        if file_type.is_dir() {
+            self.manifest.entries.push(Entry {
+                path: name.clone(),
+                kind: EntryKind::Directory,
+                size: 0,
+                metadata: Metadata::read(path, xattrs)?,
+                sha256: None,
+            });
+            let mut children = std::fs::read_dir(path)
+                .with_context(|| format!("Failed to list {}", path.display()))?
+                .collect::<std::io::Result<Vec<_>>>()?;
+            children.sort_by_key(|child| child.file_name());
+            for child in children {
+                let child_name = child.file_name();
+                let child_name = child_name
+                    .to_str()
+                    .with_context(|| format!("File name is not valid UTF-8: {}", child.path().display()))?;
+                self.add(&child.path(), format!("{}/{}", name, child_name), xattrs)?;
+            }
+        } else if file_type.is_file() {
+            let mut hasher = Sha256::new();
+            let mut file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+            let size = std::io::copy(&mut file, &mut hasher)?;
+            self.manifest.entries.push(Entry {
+                path: name,
+                kind: EntryKind::File,
+                size,
+                metadata: Metadata::read(path, xattrs)?,
+                sha256: Some(format!("{:x}", hasher.finalize())),
+            });
+            self.sources.push(path.to_path_buf());
+        } else {
+            eprintln!("Skipping {}: not a regular file or directory", path.display());
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> Result<u64> {
+        self.manifest.stream_size()
+    }
+
+    /// The archive stream, read from the files as it goes.
+    pub fn reader(&self) -> Result<ArchiveReader<'_>> {
+        Ok(ArchiveReader {
+            prefix: Cursor::new(self.manifest.to_bytes()?),
+            files: self.manifest.files().zip(&self.sources).collect::<Vec<_>>().into_iter(),
+            current: None,
+        })
+    }
+}
+
+/// Reads the manifest, then every file in turn. A file that changed since
+/// it was scanned fails the read, rather than leaving a wrong hash behind.
+pub struct ArchiveReader<'a> {
+    prefix: Cursor<Vec<u8>>,
+    files: std::vec::IntoIter<(&'a Entry, &'a PathBuf)>,
+    current: Option<(&'a Entry, std::io::Take<File>, Sha256)>,
+}
+
+impl Read for ArchiveReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.prefix.read(buf)?;
+        if n > 0 {
+            return Ok(n);
+        }
+        loop {
+            if let Some((entry, file, hasher)) = &mut self.current {
+                let n = file.read(buf)?;
+                if n > 0 {
+                    hasher.update(&buf[..n]);
+                    return Ok(n);
+                }
+                let hash = format!("{:x}", std::mem::take(hasher).finalize());
+                if file.limit() > 0 || entry.sha256.as_ref() != Some(&hash) {
+                    return Err(std::io::Error::other(format!("{} changed while encoding", entry.path)));
+                }
+                self.current = None;
+            }
+            let Some((entry, path)) = self.files.next() else {
+                return Ok(0);
+            };
+            let file = File::open(path)?.take(entry.size);
+            self.current = Some((entry, file, Sha256::new()));
+        }
+    }
+}
+
+/// Unpacks an archive stream as the decoder writes it: the manifest first,
+/// then each file in turn, checked against its hash. Without a root
+/// directory, the files are only checked.
+pub struct ArchiveWriter {
+    root: Option<PathBuf>,
+    prefix: Vec<u8>,
+    manifest: Option<Manifest>,
+    next: usize,
+    current: Option<OpenFile>,
+    damaged: Vec<String>,
+    restore: Restore,
+    /// Refuse to replace existing files, when the video chose where they go
+    no_clobber: bool,
+}
+
+struct OpenFile {
+    entry: usize,
+    file: Option<File>,
+    hasher: Sha256,
+    remaining: u64,
+}
+
+impl ArchiveWriter {
+    pub fn new(root: Option<PathBuf>, restore: Restore, no_clobber: bool) -> Result<Self> {
+        if let Some(root) = &root {
+            std::fs::create_dir_all(root).with_context(|| format!("Failed to create {}", root.display()))?;
+        }
+        Ok(Self { root, prefix: Vec::new(), manifest: None, next: 0, current: None, damaged: Vec::new(), restore, no_clobber })
+    }
+
+    /// Collects the stream prefix until the manifest is complete, returns how much of `data` it took.
+    fn read_manifest(&mut self, data: &[u8]) -> Result<usize> {
+        let needed = match self.prefix.len() < PREFIX_SIZE {
+            true => PREFIX_SIZE,
+            false => PREFIX_SIZE + Manifest::length(&self.prefix)?,
+        };
+        let n = (needed - self.prefix.len()).min(data.len());
+        self.prefix.extend_from_slice(&data[..n]);
+        if self.prefix.len() == needed && needed > PREFIX_SIZE {
+            let manifest: Manifest =
+                serde_json::from_slice(&self.prefix[PREFIX_SIZE..]).context("Archive manifest is damaged")?;
+            // Check every path before anything is written
+            if let Some(root) = &self.root {
+                for entry in &manifest.entries {
+                    safe_path(root, &entry.path)?;
+                }
+            }
+            self.manifest = Some(manifest);
+            self.advance()?;
+        }
+        Ok(n)
+    }
+
+    /// Creates directories up to the next file with content, and opens it.
+    fn advance(&mut self) -> Result<()> {
+        while self.current.is_none() {
+            let index = self.next;
+            let Some(entry) = self.manifest.as_ref().and_then(|m| m.entries.get(index)).cloned() else {
+                break;
+            };
+            self.next += 1;
+            let path = self.root.as_ref().map(|root| safe_path(root, &entry.path)).transpose()?;
+            match entry.kind {
+                EntryKind::Directory => {
+                    if let Some(path) = &path {
+                        std::fs::create_dir_all(path).with_context(|| format!("Failed to create {}", path.display()))?;
+                    }
+                }
+                EntryKind::File => {
+                    let mut options = std::fs::OpenOptions::new();
+                    options.write(true).create(true);
+                    match self.no_clobber {
+                        true => options.create_new(true),
+                        false => options.truncate(true),
+                    };
+                    let file = path
+                        .as_ref()
+                        .map(|path| options.open(path).with_context(|| format!("Failed to create {}", path.display())))
+                        .transpose()?;
+                    self.current = Some(OpenFile { entry: index, file, hasher: Sha256::new(), remaining: entry.size });
+                    if entry.size == 0 {
+                        self.close_file()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn close_file(&mut self) -> Result<()> {
+        let Some(open) = self.current.take() else {
+            return Ok(());
+        };
+        let manifest = self.manifest.as_ref().context("No manifest")?;
+        let entry = &manifest.entries[open.entry];
+        if entry.sha256 != Some(format!("{:x}", open.hasher.finalize())) {
+            self.damaged.push(entry.path.clone());
+        }
+        if let (Some(root), Some(file)) = (&self.root, open.file) {
+            drop(file);
+            entry.metadata.apply(&safe_path(root, &entry.path)?, &self.restore)?;
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            if self.manifest.is_none() {
+                let n = self.read_manifest(data)?;
+                data = &data[n..];
+                continue;
+            }
+            let Some(open) = &mut self.current else {
+                // Past the last file, nothing should be left
+                break;
+            };
+            let n = (open.remaining.min(data.len() as u64)) as usize;
+            if let Some(file) = &mut open.file {
+                file.write_all(&data[..n])?;
+            }
+            open.hasher.update(&data[..n]);
+            open.remaining -= n as u64;
+            data = &data[n..];
+            if open.remaining == 0 {
+                self.close_file()?;
+                self.advance()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores directory times and modes, now that their contents are
+    /// written. Returns the files that didn't match their hash or never
+    /// came through.
+    pub fn finish(mut self) -> Result<Vec<String>> {
+        let manifest = self.manifest.take().context("The archive manifest is missing")?;
+        if let Some(open) = &self.current {
+            self.damaged.push(manifest.entries[open.entry].path.clone());
+        }
+        let missing = manifest.entries[self.next..].iter().filter(|e| e.kind == EntryKind::File);
+        self.damaged.extend(missing.map(|e| e.path.clone()));
+        if let Some(root) = &self.root {
+            // Deepest first, so setting a directory's time isn't undone by its children
+            for entry in manifest.entries[..self.next].iter().rev().filter(|e| e.kind == EntryKind::Directory) {
+                entry.metadata.apply(&safe_path(root, &entry.path)?, &self.restore)?;
+            }
+        }
+        Ok(self.damaged)
+    }
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write_data(buf).map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where an archive path lands under `root`. Refuses absolute paths, `..`
+/// and symlinks already in the output directory, anything that could
+/// write outside of it.
+pub fn safe_path(root: &Path, path: &str) -> Result<PathBuf> {
+    let mut target = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => target.push(part),
+            _ => return Err(anyhow::anyhow!("Refusing unsafe path in archive: {}", path)),
+        }
+        if target.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(anyhow::anyhow!("Refusing to write through symlink {}", target.display()));
+        }
+    }
+    if target == root {
+        return Err(anyhow::anyhow!("Refusing empty path in archive"));
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("b2v-archive-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let source = temp_dir("source");
+        let tree = source.join("tree");
+        std::fs::create_dir_all(tree.join("sub/empty")).unwrap();
+        std::fs::write(tree.join("a.txt"), b"hello").unwrap();
+        std::fs::write(tree.join("sub/b.bin"), vec![7u8; 100_000]).unwrap();
+        std::fs::write(tree.join("sub/zero"), b"").unwrap();
+        std::fs::write(source.join("single.txt"), b"single").unwrap();
+
+        let inputs = [tree.to_str().unwrap().to_string(), source.join("single.txt").to_str().unwrap().to_string()];
+        let archive = Archive::scan(&inputs, false).unwrap();
+        let paths: Vec<_> = archive.manifest.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["tree", "tree/a.txt", "tree/sub", "tree/sub/b.bin", "tree/sub/empty", "tree/sub/zero", "single.txt"]);
+
+        let mut stream = Vec::new();
+        archive.reader().unwrap().read_to_end(&mut stream).unwrap();
+        assert_eq!(stream.len() as u64, archive.size().unwrap());
+        let (manifest, prefix) = Manifest::read(&mut &stream[..], stream.len() as u64).unwrap();
+        assert_eq!(manifest, archive.manifest);
+        assert_eq!(prefix, manifest.to_bytes().unwrap());
+        // A length past the end of the stream is refused before anything is read by it
+        let mut crafted = prefix.clone();
+        crafted[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Manifest::read(&mut &crafted[..], stream.len() as u64).is_err());
+        assert!(Manifest::read(&mut &prefix[..prefix.len() - 1], stream.len() as u64).is_err());
+
+        // Written in odd pieces, as chunks would arrive
+        let output = temp_dir("output");
+        let mut writer = ArchiveWriter::new(Some(output.clone()), Restore::default(), false).unwrap();
+        for piece in stream.chunks(777) {
+            writer.write_all(piece).unwrap();
+        }
+        assert!(writer.finish().unwrap().is_empty());
+        assert_eq!(std::fs::read(output.join("tree/sub/b.bin")).unwrap(), vec![7u8; 100_000]);
+        assert_eq!(std::fs::read(output.join("single.txt")).unwrap(), b"single");
+        assert!(output.join("tree/sub/empty").is_dir());
+        assert_eq!(std::fs::read(output.join("tree/sub/zero")).unwrap(), b"");
+
+        // Into the current directory by default, where nothing may be replaced
+        std::fs::write(output.join("single.txt"), b"mine").unwrap();
+        let mut writer = ArchiveWriter::new(Some(output.clone()), Restore::default(), true).unwrap();
+        assert!(writer.write_all(&stream).is_err());
+        assert_eq!(std::fs::read(output.join("single.txt")).unwrap(), b"mine");
+
+        // Damage is pinned on the file it hit
+        let last = stream.len() - 1;
+        stream[last] ^= 1;
+        let mut writer = ArchiveWriter::new(None, Restore::default(), false).unwrap();
+        writer.write_all(&stream[..stream.len() - 3]).unwrap();
+        let mut checker = ArchiveWriter::new(None, Restore::default(), false).unwrap();
+        checker.write_all(&stream).unwrap();
+        assert_eq!(checker.finish().unwrap(), ["single.txt"]);
+        assert_eq!(writer.finish().unwrap(), ["single.txt"]);
+
+        let _ = std::fs::remove_dir_all(source);
+        let _ = std::fs::remove_dir_all(output);
+    }
+
+    #[test]
+    fn test_safe_path() {
+        let root = Path::new("/restore");
+        assert_eq!(safe_path(root, "a/b.txt").unwrap(), Path::new("/restore/a/b.txt"));
+        assert!(safe_path(root, "../etc/passwd").is_err());
+        assert!(safe_path(root, "a/../../x").is_err());
+        assert!(safe_path(root, "/etc/passwd").is_err());
+        assert!(safe_path(root, "").is_err());
+    }
+}
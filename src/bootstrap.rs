@@ -0,0 +1,119 @@
+use crate::utils::{majority_vote, HEADER_SIZE};
+use anyhow::{Context, Result};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// Fixed RS code of the header/trailer payload in bootstrap frames. Unlike
+/// the data frames it can't depend on anything the header says.
+pub const BOOTSTRAP_DATA_SHARDS: usize = 8;
+pub const BOOTSTRAP_PARITY_SHARDS: usize = 4;
+
+/// Payload plus its CRC32, split over the data shards.
+const SHARD_SIZE: usize = (HEADER_SIZE + 4).div_ceil(BOOTSTRAP_DATA_SHARDS);
+
+/// Each shard carries its own CRC32, so damaged ones become erasures.
+const STORED_SHARD_SIZE: usize = SHARD_SIZE + 4;
+
+/// Bytes of one protected copy. Bootstrap frames repeat it as often as it fits.
+pub const PROTECTED_SIZE: usize = STORED_SHARD_SIZE * (BOOTSTRAP_DATA_SHARDS + BOOTSTRAP_PARITY_SHARDS);
+
+/// Protects a `HEADER_SIZE` payload for a bootstrap frame: a CRC32 over the
+/// whole payload, then the RS shards, each followed by its own CRC32.
+pub fn protect(payload: &[u8]) -> Result<Vec<u8>> {
+    if payload.len() != HEADER_SIZE {
+        return Err(anyhow::anyhow!("Bootstrap payload must be {} bytes", HEADER_SIZE));
+    }
+    let mut data = payload.to_vec();
+    data.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    data.resize(SHARD_SIZE * BOOTSTRAP_DATA_SHARDS, 0);
+
+    let mut shards: Vec<Vec<u8>> = data.chunks(SHARD_SIZE).map(|s| s.to_vec()).collect();
+    shards.resize(BOOTSTRAP_DATA_SHARDS + BOOTSTRAP_PARITY_SHARDS, vec![0u8; SHARD_SIZE]);
+    ReedSolomon::new(BOOTSTRAP_DATA_SHARDS, BOOTSTRAP_PARITY_SHARDS)?.encode(&mut shards)?;
+
+    let mut protected = Vec::with_capacity(PROTECTED_SIZE);
+    for shard in &shards {
+        protected.extend_from_slice(shard);
+        protected.extend_from_slice(&crc32fast::hash(shard).to_be_bytes());
+    }
+    Ok(protected)
+}
+
+/// Recovers the payload from the repeated protected copies read off a
+/// bootstrap frame. Each shard is taken from the bitwise majority of all
+/// copies, or failing that from any single copy whose CRC still matches;
+/// Reed-Solomon fills in shards that are damaged in every copy.
+pub fn recover(frame_data: &[u8]) -> Result<Vec<u8>> {
+    let copies: Vec<&[u8]> = frame_data.chunks_exact(PROTECTED_SIZE).collect();
+    if copies.is_empty() {
+        return Err(anyhow::anyhow!("Bootstrap frame too small for a header"));
+    }
+
+    let mut shards: Vec<Option<Vec<u8>>> = (0..BOOTSTRAP_DATA_SHARDS + BOOTSTRAP_PARITY_SHARDS)
+        .map(|index| {
+            let range = index * STORED_SHARD_SIZE..(index + 1) * STORED_SHARD_SIZE;
+            let stored: Vec<&[u8]> = copies.iter().map(|c| &c[range.clone()]).collect();
+            let voted = majority_vote(&stored);
+            std::iter::once(voted.as_slice())
+                .chain(stored)
+                .find(|s| s[SHARD_SIZE..] == crc32fast::hash(&s[..SHARD_SIZE]).to_be_bytes())
+                .map(|s| s[..SHARD_SIZE].to_vec())
+        })
+        .collect();
+
+    ReedSolomon::new(BOOTSTRAP_DATA_SHARDS, BOOTSTRAP_PARITY_SHARDS)?
+        .reconstruct_data(&mut shards)
+        .ok()
+        .context("Bootstrap frame too damaged to recover")?;
+
+    let data: Vec<u8> = shards
+        .iter()
+        .take(BOOTSTRAP_DATA_SHARDS)
+        .flat_map(|s| s.as_deref().unwrap_or_default())
+        .copied()
+        .collect();
+    let (payload, crc) = data[..HEADER_SIZE + 4].split_at(HEADER_SIZE);
+    if crc != crc32fast::hash(payload).to_be_bytes() {
+        return Err(anyhow::anyhow!("Bootstrap payload CRC mismatch"));
+    }
+    Ok(payload.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_of_copies(payload: &[u8], copies: usize) -> Vec<u8> {
+        protect(payload).unwrap().repeat(copies)
+    }
+
+    #[test]
+    fn test_recovers_damaged_header() {
+        let payload: Vec<u8> = (0..HEADER_SIZE).map(|i| (i % 251) as u8).collect();
+
+        // Scattered bit flips in every copy are outvoted
+        let mut frame = frame_of_copies(&payload, 5);
+        for (i, byte) in frame.iter_mut().enumerate().step_by(97) {
+            *byte ^= 1 << (i % 8);
+        }
+        assert_eq!(recover(&frame).unwrap(), payload);
+
+        // The first copy wiped out entirely: the others still vote it down
+        let mut frame = frame_of_copies(&payload, 3);
+        frame[..PROTECTED_SIZE].fill(0);
+        assert_eq!(recover(&frame).unwrap(), payload);
+
+        // A single copy with a few shards destroyed: RS rebuilds them
+        let mut frame = frame_of_copies(&payload, 1);
+        for shard in [0, 5, 9] {
+            frame[shard * STORED_SHARD_SIZE + 10] ^= 0xFF;
+        }
+        assert_eq!(recover(&frame).unwrap(), payload);
+
+        // Too many shards gone
+        for shard in [1, 2] {
+            frame[shard * STORED_SHARD_SIZE] ^= 0xFF;
+        }
+        assert!(recover(&frame).is_err());
+        assert!(recover(&[0u8; 100]).is_err());
+    }
+}
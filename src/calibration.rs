@@ -0,0 +1,281 @@
+use crate::modulation::{gray_decode, Modulation, MAX_BITS_PER_BLOCK};
+use std::ops::Range;
+
+/// Gray patches across the top of every bootstrap frame, from black to white
+/// in even steps. Enough of them to cover every level of the densest modulation.
+pub const CALIBRATION_PATCHES: usize = 1 << MAX_BITS_PER_BLOCK;
+
+/// Height of the patch band, in bootstrap grid pixels.
+pub const CALIBRATION_HEIGHT: usize = 32;
+
+/// Platforms shrinking the range further than this are beyond saving, and a
+/// band that flat is more likely a damaged frame than a real transfer curve.
+const MIN_CONTRAST: f64 = 32.0;
+
+/// Level refinement rounds per band. Converges well before this in practice.
+const REFINE_ROUNDS: usize = 8;
+
+/// Nominal value of calibration patch `index`.
+pub fn patch_value(index: usize) -> u8 {
+    (index * 255 / (CALIBRATION_PATCHES - 1)) as u8
+}
+
+/// Columns of patch `index` on a grid `width` pixels wide. The last patch
+/// takes up whatever is left over.
+pub fn patch_columns(width: usize, index: usize) -> Range<usize> {
+    let patch_width = width / CALIBRATION_PATCHES;
+    let end = if index + 1 == CALIBRATION_PATCHES { width } else { (index + 1) * patch_width };
+    index * patch_width..end
+}
+
+/// Where each channel's values ended up after the platform's transcode, as
+/// measured on the calibration patches of the header frame. TV-range
+/// conversion, gamma or brightness normalisation all shift these away from
+/// the values we drew.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    observed: [[f64; CALIBRATION_PATCHES]; 3],
+}
+
+impl Calibration {
+    /// No shift at all: every patch reads back as drawn.
+    pub fn nominal() -> Self {
+        let curve: [f64; CALIBRATION_PATCHES] = std::array::from_fn(|i| patch_value(i) as f64);
+        Self { observed: [curve; 3] }
+    }
+
+    /// Builds a calibration from the measured patch values, or `None` if they
+    /// don't form a usable (rising, high enough contrast) curve in every channel.
+    pub fn from_patches(observed: [[f64; CALIBRATION_PATCHES]; 3]) -> Option<Self> {
+        let usable = observed.iter().all(|curve| {
+            curve.windows(2).all(|pair| pair[1] > pair[0])
+                && curve[CALIBRATION_PATCHES - 1] - curve[0] >= MIN_CONTRAST
+        });
+        usable.then_some(Self { observed })
+    }
+
+    /// Observed black and white points of a channel.
+    pub fn range(&self, channel: usize) -> (f64, f64) {
+        let curve = &self.observed[channel];
+        (curve[0], curve[CALIBRATION_PATCHES - 1])
+    }
+
+    /// Levels of `modulation` for each of its channels (just Red in gray mode).
+    pub fn channel_levels(&self, modulation: Modulation) -> Vec<Levels> {
+        (0..modulation.channels()).map(|c| self.levels(modulation, c)).collect()
+    }
+
+    /// Expected values of the levels of `modulation` in `channel`, read off
+    /// the measured curve between the two patches around each level.
+    pub fn levels(&self, modulation: Modulation, channel: usize) -> Levels {
+        let curve = &self.observed[channel];
+        let values = Levels::nominal(modulation)
+            .values
+            .iter()
+            .map(|&value| {
+                let position = value * (CALIBRATION_PATCHES - 1) as f64 / 255.0;
+                let below = (position.floor() as usize).min(CALIBRATION_PATCHES - 2);
+                let t = position - below as f64;
+                curve[below] + t * (curve[below + 1] - curve[below])
+            })
+            .collect();
+        Levels { values }
+    }
+}
+
+/// The value each level of a modulation is expected at in one channel,
+/// rising from the first level (black) to the last (white).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Levels {
+    values: Vec<f64>,
+}
+
+impl Levels {
+    /// Levels exactly where the encoder draws them.
+    pub fn nominal(modulation: Modulation) -> Self {
+        let values = (0..modulation.levels() as u8)
+            .map(|level| {
+                // Symbols are Gray-coded, so level n is drawn by symbol n ^ (n >> 1)
+                modulation.symbol_to_value(level ^ (level >> 1)) as f64
+            })
+            .collect();
+        Self { values }
+    }
+
+    /// Nearest level for a sampled (possibly averaged) value, as its symbol,
+    /// and how sure the reading is: 255 right on a level, 0 halfway between two.
+    pub fn classify(&self, value: f64) -> (u8, u8) {
+        let values = &self.values;
+        let max_level = (values.len() - 1) as f64;
+        // Position in level space, linear between the two levels around value
+        let below = values
+            .partition_point(|&v| v <= value)
+            .clamp(1, values.len() - 1)
+            - 1;
+        let step = values[below + 1] - values[below];
+        let position = (below as f64 + (value - values[below]) / step).clamp(0.0, max_level);
+        let level = position.round();
+        let confidence = (1.0 - 2.0 * (position - level).abs()) * 255.0;
+        let level = level as u8;
+        (level ^ (level >> 1), confidence.round() as u8)
+    }
+
+    /// How far `value` lies inside the range that reads as `symbol`, whose
+    /// edges are the thresholds halfway to the neighbouring levels. Negative
+    /// if it reads as another symbol, by how far it strayed.
+    pub fn margin(&self, value: f64, symbol: u8) -> f64 {
+        let values = &self.values;
+        let level = (gray_decode(symbol) as usize).min(values.len() - 1);
+        let below = match level {
+            0 => f64::INFINITY,
+            _ => value - (values[level - 1] + values[level]) / 2.0,
+        };
+        let above = match values.get(level + 1) {
+            Some(next) => (values[level] + next) / 2.0 - value,
+            None => f64::INFINITY,
+        };
+        below.min(above)
+    }
+
+    /// The same levels moved and scaled so the first and last sit at `black`
+    /// and `white`, as measured on one frame. Levels in between keep their
+    /// relative spacing, so the calibrated curve still holds.
+    pub fn stretch(&self, black: f64, white: f64) -> Self {
+        let (first, last) = (self.values[0], self.values[self.values.len() - 1]);
+        if white - black < MIN_CONTRAST {
+            return self.clone();
+        }
+        let values = self
+            .values
+            .iter()
+            .map(|&v| black + (v - first) * (white - black) / (last - first))
+            .collect();
+        Self { values }
+    }
+
+    /// Adapts the levels to one frame from a histogram of its block values,
+    /// by iteratively moving each level to the mean of the values closest to
+    /// it (the isodata/Ridler-Calvard threshold for binary frames). Levels the
+    /// frame barely uses keep their calibrated value.
+    pub fn refine(&self, histogram: &[u32; 256]) -> Self {
+        let total: u64 = histogram.iter().map(|&n| n as u64).sum();
+        let min_members = (total / 256).max(1);
+        let mut values = self.values.clone();
+        for _ in 0..REFINE_ROUNDS {
+            let mut sums = vec![(0u64, 0u64); values.len()];
+            for (value, &count) in histogram.iter().enumerate() {
+                if count == 0 {
+                    continue;
+                }
+                // Thresholds sit halfway between neighbouring levels
+                let nearest = values
+                    .windows(2)
+                    .take_while(|pair| value as f64 > (pair[0] + pair[1]) / 2.0)
+                    .count();
+                sums[nearest].0 += value as u64 * count as u64;
+                sums[nearest].1 += count as u64;
+            }
+            let next: Vec<f64> = values
+                .iter()
+                .zip(&sums)
+                .map(|(&old, &(sum, members))| {
+                    if members >= min_members { sum as f64 / members as f64 } else { old }
+                })
+                .collect();
+            // Keep the levels strictly rising, or classification breaks down
+            if next.windows(2).any(|pair| pair[1] <= pair[0]) || next == values {
+                break;
+            }
+            values = next;
+        }
+        Self { values }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a TV-range (16-235) conversion does to full-range values.
+    fn tv_range(value: f64) -> f64 {
+        16.0 + value * 219.0 / 255.0
+    }
+
+    #[test]
+    fn test_calibration_follows_level_shift() {
+        let curve: [f64; CALIBRATION_PATCHES] = std::array::from_fn(|i| tv_range(patch_value(i) as f64));
+        let calibration = Calibration::from_patches([curve; 3]).unwrap();
+        assert_eq!(calibration.range(0), (16.0, 235.0));
+
+        let modulation = Modulation::new(3, false).unwrap();
+        let nominal = Levels::nominal(modulation);
+        let levels = calibration.levels(modulation, 0);
+        for symbol in 0..modulation.levels() as u8 {
+            let drawn = modulation.symbol_to_value(symbol) as f64;
+            assert_eq!(levels.classify(tv_range(drawn)), (symbol, 255));
+        }
+        // Uncalibrated, the squeezed white lands a level short
+        let white = modulation.symbol_to_value(0b100) as f64;
+        assert_eq!(levels.classify(tv_range(white)).0, 0b100);
+        assert_ne!(nominal.classify(tv_range(white)).0, 0b100);
+
+        // Flat or falling patches are a damaged frame, not a calibration
+        assert!(Calibration::from_patches([[128.0; CALIBRATION_PATCHES]; 3]).is_none());
+    }
+
+    #[test]
+    fn test_refine_tracks_reduced_contrast() {
+        // A binary frame whose black and white were squeezed to 90 and 160
+        let mut histogram = [0u32; 256];
+        histogram[80..=100].fill(50);
+        histogram[150..=170].fill(40);
+        let levels = Levels::nominal(Modulation::BINARY).refine(&histogram);
+        assert_eq!(levels.classify(95.0).0, 0);
+        assert_eq!(levels.classify(130.0).0, 1);
+        assert_eq!(levels.classify(160.0), (1, 255));
+    }
+
+    #[test]
+    fn test_stretch_follows_brightness_drift() {
+        let modulation = Modulation::new(2, false).unwrap();
+        let levels = Levels::nominal(modulation).stretch(96.0, 226.0);
+        for symbol in 0..4 {
+            let drawn = modulation.symbol_to_value(symbol) as f64;
+            assert_eq!(levels.classify(96.0 + drawn * 130.0 / 255.0).0, symbol);
+        }
+        // Nothing to go on in a flat frame
+        assert_eq!(Levels::nominal(modulation).stretch(100.0, 110.0), Levels::nominal(modulation));
+    }
+
+    #[test]
+    fn test_classify_confidence() {
+        let levels = Levels::nominal(Modulation::BINARY);
+        assert_eq!(levels.classify(0.0), (0, 255));
+        assert_eq!(levels.classify(255.0), (1, 255));
+        assert_eq!(levels.classify(191.25), (1, 128));
+        assert!(levels.classify(127.0).1 < 2);
+
+        let modulation = Modulation::new(2, false).unwrap();
+        let levels = Levels::nominal(modulation);
+        for symbol in 0..4 {
+            let value = modulation.symbol_to_value(symbol) as f64;
+            assert_eq!(levels.classify(value), (symbol, 255));
+        }
+    }
+
+    #[test]
+    fn test_margin_from_thresholds() {
+        let levels = Levels::nominal(Modulation::BINARY);
+        assert_eq!(levels.margin(255.0, 1), 127.5);
+        assert_eq!(levels.margin(100.0, 0), 27.5);
+        assert_eq!(levels.margin(100.0, 1), -27.5);
+
+        // Inner levels have a threshold on either side: 0, 85, 170, 255
+        let modulation = Modulation::new(2, false).unwrap();
+        let levels = Levels::nominal(modulation);
+        let symbol = levels.classify(85.0).0;
+        assert_eq!(levels.margin(85.0, symbol), 42.5);
+        assert_eq!(levels.margin(120.0, symbol), 7.5);
+        assert_eq!(levels.margin(130.0, symbol), -2.5);
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Read;
+
+const ALGORITHM_ZSTD: u8 = 1;
+
+/// How much of the input is test-compressed to decide whether compressing is worth it
+pub const SAMPLE_SIZE: usize = 1 << 20;
+/// Compression has to save at least this share of the sample (in percent)
+const MIN_SAVINGS: usize = 5;
+
+/// Each chunk starts with the offset of its data in the input
+const OFFSET_SIZE: usize = 8;
+/// Most input a chunk takes, as a multiple of its size. Keeps what is read
+/// ahead, and what a crafted chunk can make the decoder allocate, bounded.
+const MAX_RATIO: usize = 32;
+
+/// The `EXT_COMPRESSION` header extension: every chunk holds the offset of
+/// its data in the input and that data compressed as a zstd frame of its
+/// own, zero-padded. A lost chunk loses only its own data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// Level the encoder used, for information only
+    pub level: i32,
+}
+
+impl Compression {
+    pub fn new(level: i32) -> Self {
+        Self { level }
+    }
+
+    /// Test-compresses the start of the input, so already compressed data
+    /// (archives, media, encrypted files) isn't run through zstd for nothing.
+    pub fn worth_it(&self, sample: &[u8]) -> Result<bool> {
+        let sample = &sample[..sample.len().min(SAMPLE_SIZE)];
+        let compressed = zstd::bulk::compress(sample, self.level).context("Failed to compress sample")?;
+        Ok(compressed.len() * 100 < sample.len() * (100 - MIN_SAVINGS))
+    }
+
+    /// Input that fits a chunk of `payload_size` bytes however badly it
+    /// compresses. Every chunk but the last takes at least this much. 0 if
+    /// chunks are too small to hold a frame at all.
+    pub fn min_input(payload_size: usize) -> usize {
+        let capacity = payload_size.saturating_sub(OFFSET_SIZE);
+        let (mut fits, mut too_big) = (0, capacity + 1);
+        while too_big - fits > 1 {
+            let n = (fits + too_big) / 2;
+            match zstd::zstd_safe::compress_bound(n) <= capacity {
+                true => fits = n,
+                false => too_big = n,
+            }
+        }
+        fits
+    }
+
+    /// Most chunks an input of `file_size` bytes can take
+    pub fn max_chunks(file_size: u64, payload_size: usize) -> u64 {
+        file_size.div_ceil(Self::min_input(payload_size).max(1) as u64)
+    }
+
+    /// Reads a chunk back: the offset of its data in the input, and the data.
+    /// `None` if it is damaged or would reach past `file_size`.
+    pub fn unpack(chunk: &[u8], file_size: u64) -> Option<(u64, Vec<u8>)> {
+        let offset = (&chunk[..chunk.len().min(OFFSET_SIZE)]).read_u64::<BigEndian>().ok()?;
+        let frame = chunk.get(OFFSET_SIZE..)?;
+        let frame_size = zstd::zstd_safe::find_frame_compressed_size(frame).ok()?;
+        let limit = file_size.checked_sub(offset)?.min((chunk.len() * MAX_RATIO) as u64);
+        let data = zstd::bulk::decompress(&frame[..frame_size], limit as usize).ok()?;
+        Some((offset, data))
+    }
+
+    pub fn to_bytes(self) -> Result<Vec<u8>> {
+        let mut bytes = vec![ALGORITHM_ZSTD];
+        bytes.write_i32::<BigEndian>(self.level)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let algorithm = bytes.read_u8()?;
+        if algorithm != ALGORITHM_ZSTD {
+            return Err(anyhow::anyhow!("Unsupported compression (algorithm {})", algorithm));
+        }
+        Ok(Self { level: bytes.read_i32::<BigEndian>()? })
+    }
+}
+
+/// The stored stream of a compressed input, in whole chunks of `payload_size`
+/// bytes as `Compression` lays them out. Each chunk takes as much input as
+/// its frame can hold, judged by how well the last one compressed.
+pub struct ChunkCompressor<R: Read> {
+    reader: R,
+    level: i32,
+    payload_size: usize,
+    /// Input read ahead, not yet in a chunk
+    pending: Vec<u8>,
+    /// Offset in the input of `pending`
+    offset: u64,
+    /// Input the next chunk is tried with
+    target: usize,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> ChunkCompressor<R> {
+    pub fn new(reader: R, level: i32, payload_size: usize) -> Result<Self> {
+        let min_input = Compression::min_input(payload_size);
+        if min_input == 0 {
+            return Err(anyhow::anyhow!("Chunks of {} bytes are too small to compress", payload_size));
+        }
+        Ok(Self {
+            reader,
+            level,
+            payload_size,
+            pending: Vec::new(),
+            offset: 0,
+            target: min_input * 4,
+            chunk: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Packs the next chunk. Returns false at the end of the input.
+    fn next_chunk(&mut self) -> std::io::Result<bool> {
+        let capacity = self.payload_size - OFFSET_SIZE;
+        let min_input = Compression::min_input(self.payload_size);
+        let max_input = capacity * MAX_RATIO;
+        if self.pending.len() < self.target {
+            let wanted = (self.target - self.pending.len()) as u64;
+            (&mut self.reader).take(wanted).read_to_end(&mut self.pending)?;
+        }
+        if self.pending.is_empty() {
+            return Ok(false);
+        }
+
+        let mut n = self.target.min(self.pending.len());
+        let frame = loop {
+            let frame = zstd::bulk::compress(&self.pending[..n], self.level)?;
+            if frame.len() <= capacity {
+                break frame;
+            }
+            // Shrink by how far off it was, down to what always fits
+            n = (n * capacity / frame.len() * 9 / 10).clamp(min_input, n - 1);
+        };
+        self.target = (n * capacity / frame.len().max(1)).clamp(min_input, max_input);
+
+        self.chunk.clear();
+        self.chunk.extend_from_slice(&self.offset.to_be_bytes());
+        self.chunk.extend_from_slice(&frame);
+        self.chunk.resize(self.payload_size, 0);
+        self.position = 0;
+        self.pending.drain(..n);
+        self.offset += n as u64;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for ChunkCompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.chunk.len() && !self.next_chunk()? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_decompress_on_their_own() {
+        let input: Vec<u8> = (0..200_000u32).flat_map(|i| (i % 1000).to_be_bytes()).collect();
+        let compression = Compression::new(3);
+        assert!(compression.worth_it(&input).unwrap());
+        assert_eq!(Compression::from_bytes(&compression.to_bytes().unwrap()).unwrap(), compression);
+
+        let payload_size = 10_000;
+        let mut stream = Vec::new();
+        ChunkCompressor::new(&input[..], 3, payload_size).unwrap().read_to_end(&mut stream).unwrap();
+        assert_eq!(stream.len() % payload_size, 0);
+        let chunks: Vec<&[u8]> = stream.chunks(payload_size).collect();
+        assert!((chunks.len() as u64) < Compression::max_chunks(input.len() as u64, payload_size) / 4);
+
+        let size = input.len() as u64;
+        let mut output = Vec::new();
+        for chunk in &chunks {
+            let (offset, data) = Compression::unpack(chunk, size).unwrap();
+            assert_eq!(offset, output.len() as u64);
+            output.extend_from_slice(&data);
+        }
+        assert_eq!(output, input);
+
+        // A lost chunk takes nothing after it along
+        let (offset, data) = Compression::unpack(chunks[2], size).unwrap();
+        assert_eq!(&input[offset as usize..offset as usize + data.len()], &data[..]);
+        assert!(Compression::unpack(&vec![0u8; payload_size], size).is_none());
+        // Nor does a chunk claiming to reach past the end
+        assert!(Compression::unpack(chunks[1], offset - 1).is_none());
+
+        // Data that doesn't compress still fits, at the rate of min_input
+        let mut noise = vec![0u8; 100_000];
+        getrandom::getrandom(&mut noise).unwrap();
+        assert!(!compression.worth_it(&noise).unwrap());
+        let mut stream = Vec::new();
+        ChunkCompressor::new(&noise[..], 3, payload_size).unwrap().read_to_end(&mut stream).unwrap();
+        assert!((stream.len() / payload_size) as u64 <= Compression::max_chunks(noise.len() as u64, payload_size));
+        assert!(ChunkCompressor::new(&noise[..], 3, 40).is_err());
+    }
+}
@@ -0,0 +1,470 @@
+use anyhow::{Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use crate::utils::{from_hex, to_hex};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+/// Poly1305 tag appended to every encrypted chunk.
+pub const TAG_SIZE: usize = 16;
+const SALT_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+
+// Algorithm identifiers recorded in the header, so either can be swapped later
+const CIPHER_XCHACHA20_POLY1305: u8 = 1;
+const KDF_ARGON2ID: u8 = 1;
+const KEY_WRAP_X25519: u8 = 1;
+
+// How keys are written out: a prefix, then the 32 key bytes in hex
+const PUBLIC_KEY_PREFIX: &str = "B2V-PUBLIC-KEY-";
+const SECRET_KEY_PREFIX: &str = "B2V-SECRET-KEY-";
+
+/// Domain separation of the key wrapping KDF.
+const WRAP_INFO: &[u8] = b"b2v X25519 file key";
+
+/// One recipient's copy of the file key: an ephemeral public key and the
+/// file key sealed under the secret shared with the recipient.
+const STANZA_SIZE: usize = 32 + KEY_SIZE + TAG_SIZE;
+
+/// Nonce index of the key check, out of reach of any chunk index.
+const CHECK_INDEX: u64 = u64::MAX;
+/// Nonce index the archive index is sealed with, likewise.
+pub const INDEX_NONCE: u64 = u64::MAX - 1;
+
+/// Argon2id cost parameters. Recorded in the header, so they can be raised
+/// for new videos without breaking old ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// Highest costs a header may ask for. The header isn't authenticated
+    /// before the key is derived, so a crafted one could otherwise demand
+    /// terabytes of memory or hours of hashing.
+    pub const MAX: Self = Self { memory_kib: 4 * 1024 * 1024, iterations: 64, parallelism: 16 };
+
+    fn check(&self) -> Result<()> {
+        let max = Self::MAX;
+        if self.memory_kib > max.memory_kib || self.iterations > max.iterations || self.parallelism > max.parallelism {
+            return Err(anyhow::anyhow!(
+                "KDF parameters out of bounds ({} KiB, {} iterations, parallelism {}), at most {} KiB, {} and {} are accepted",
+                self.memory_kib,
+                self.iterations,
+                self.parallelism,
+                max.memory_kib,
+                max.iterations,
+                max.parallelism
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+/// How a video was encrypted, as recorded in the header: the KDF and its
+/// salt, plus an empty message sealed with the key so a wrong passphrase is
+/// caught before any chunk is decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Encryption {
+    pub kdf: KdfParams,
+    salt: [u8; SALT_SIZE],
+    check: [u8; TAG_SIZE],
+}
+
+impl Encryption {
+    /// Picks a fresh salt and derives the key for `passphrase`. Returns the
+    /// header record and the cipher for the chunks.
+    pub fn new(passphrase: &str, kdf: KdfParams) -> Result<(Self, ChunkCipher)> {
+        let mut salt = [0u8; SALT_SIZE];
+        getrandom::getrandom(&mut salt).map_err(|e| anyhow::anyhow!("Failed to generate salt: {}", e))?;
+        let cipher = ChunkCipher::derive(passphrase, kdf, &salt)?;
+        let mut sealed = Vec::new();
+        cipher.seal(CHECK_INDEX, &mut sealed)?;
+        let check = sealed.try_into().expect("Empty message seals to a bare tag");
+        Ok((Self { kdf, salt, check }, cipher))
+    }
+
+    /// Derives the key for `passphrase` and checks it against the header.
+    pub fn unlock(&self, passphrase: &str) -> Result<ChunkCipher> {
+        let cipher = ChunkCipher::derive(passphrase, self.kdf, &self.salt)?;
+        let mut check = self.check.to_vec();
+        cipher
+            .open(CHECK_INDEX, &mut check)
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase"))?;
+        Ok(cipher)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![CIPHER_XCHACHA20_POLY1305, KDF_ARGON2ID];
+        bytes.write_u32::<BigEndian>(self.kdf.memory_kib)?;
+        bytes.write_u32::<BigEndian>(self.kdf.iterations)?;
+        bytes.write_u32::<BigEndian>(self.kdf.parallelism)?;
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.check);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let (cipher, kdf) = (bytes.read_u8()?, bytes.read_u8()?);
+        if cipher != CIPHER_XCHACHA20_POLY1305 || kdf != KDF_ARGON2ID {
+            return Err(anyhow::anyhow!("Unsupported encryption (cipher {}, KDF {})", cipher, kdf));
+        }
+        let kdf = KdfParams {
+            memory_kib: bytes.read_u32::<BigEndian>()?,
+            iterations: bytes.read_u32::<BigEndian>()?,
+            parallelism: bytes.read_u32::<BigEndian>()?,
+        };
+        kdf.check()?;
+        let mut salt = [0u8; SALT_SIZE];
+        let mut check = [0u8; TAG_SIZE];
+        bytes.read_exact(&mut salt)?;
+        bytes.read_exact(&mut check)?;
+        Ok(Self { kdf, salt, check })
+    }
+}
+
+/// XChaCha20-Poly1305 over whole chunks. The chunk index is the nonce, which
+/// is safe because every video gets its own salt and so its own key, and it
+/// ties each chunk to its place: a chunk moved or swapped fails to open.
+pub struct ChunkCipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl ChunkCipher {
+    fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self { aead: XChaCha20Poly1305::new(Key::from_slice(key)) }
+    }
+
+    fn derive(passphrase: &str, kdf: KdfParams, salt: &[u8]) -> Result<Self> {
+        let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(KEY_SIZE))
+            .map_err(|e| anyhow::anyhow!("Invalid KDF parameters: {}", e))?;
+        let mut key = [0u8; KEY_SIZE];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+        Ok(Self::new(&key))
+    }
+
+    fn nonce(index: u64) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[16..].copy_from_slice(&index.to_be_bytes());
+        nonce
+    }
+
+    /// Encrypts chunk `index` in place, appending the tag.
+    pub fn seal(&self, index: u64, chunk: &mut Vec<u8>) -> Result<()> {
+        self.aead
+            .encrypt_in_place(&Self::nonce(index), b"", chunk)
+            .map_err(|_| anyhow::anyhow!("Encryption of chunk {} failed", index))
+    }
+
+    /// Decrypts chunk `index` in place and strips the tag. Fails if the chunk
+    /// was damaged, tampered with or belongs elsewhere.
+    pub fn open(&self, index: u64, chunk: &mut Vec<u8>) -> Result<()> {
+        self.aead
+            .decrypt_in_place(&Self::nonce(index), b"", chunk)
+            .map_err(|_| anyhow::anyhow!("Chunk {} failed authentication", index))
+    }
+}
+
+/// Public key the chunks can be encrypted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", PUBLIC_KEY_PREFIX, to_hex(self.0.as_bytes()))
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s
+            .trim()
+            .strip_prefix(PUBLIC_KEY_PREFIX)
+            .with_context(|| format!("Public keys start with {}", PUBLIC_KEY_PREFIX))?;
+        Ok(Self(PublicKey::from(from_hex::<32>(hex).context("Invalid public key")?)))
+    }
+}
+
+/// Secret key able to decrypt videos encrypted to its `Recipient`.
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", self.recipient())
+    }
+}
+
+impl Identity {
+    pub fn generate() -> Result<Self> {
+        let mut secret = [0u8; 32];
+        getrandom::getrandom(&mut secret).map_err(|e| anyhow::anyhow!("Failed to generate key: {}", e))?;
+        Ok(Self(StaticSecret::from(secret)))
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    /// Writes the key file `keygen` creates: the public key as a comment,
+    /// then the secret key. Readable by the owner only where that applies.
+    pub fn write(&self, path: &str) -> Result<()> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("Failed to create identity file {}", path))?;
+        writeln!(file, "# public key: {}", self.recipient())?;
+        writeln!(file, "{}{}", SECRET_KEY_PREFIX, to_hex(self.0.as_bytes()))?;
+        Ok(())
+    }
+
+    /// Reads every secret key in an identity file, skipping comments.
+    pub fn read_file(path: &str) -> Result<Vec<Self>> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read identity file {}", path))?;
+        let identities = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let hex = line
+                    .strip_prefix(SECRET_KEY_PREFIX)
+                    .with_context(|| format!("Not a secret key in {}", path))?;
+                Ok(Self(StaticSecret::from(from_hex::<32>(hex).context("Invalid secret key")?)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if identities.is_empty() {
+            return Err(anyhow::anyhow!("No secret key found in {}", path));
+        }
+        Ok(identities)
+    }
+}
+
+/// Reads public keys from a file, one per line, skipping comments.
+pub fn read_recipients_file(path: &str) -> Result<Vec<Recipient>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read recipients file {}", path))?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+/// The header record of a video encrypted to public keys, age style: the
+/// chunks are encrypted under a random file key, and every recipient gets
+/// a copy of it wrapped for their key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipients {
+    stanzas: Vec<[u8; STANZA_SIZE]>,
+}
+
+impl Recipients {
+    /// Picks a file key and wraps it for each recipient. Returns the header
+    /// record and the cipher for the chunks.
+    pub fn new(recipients: &[Recipient]) -> Result<(Self, ChunkCipher)> {
+        if recipients.is_empty() {
+            return Err(anyhow::anyhow!("No recipients to encrypt to"));
+        }
+        let mut file_key = [0u8; KEY_SIZE];
+        getrandom::getrandom(&mut file_key).map_err(|e| anyhow::anyhow!("Failed to generate file key: {}", e))?;
+        let stanzas = recipients
+            .iter()
+            .map(|recipient| {
+                let ephemeral = Identity::generate()?;
+                let ephemeral_public = ephemeral.recipient().0;
+                let shared = ephemeral.0.diffie_hellman(&recipient.0);
+                let wrap = Self::wrap_cipher(shared, &ephemeral_public, &recipient.0)?;
+                let mut wrapped = file_key.to_vec();
+                wrap.seal(0, &mut wrapped)?;
+                let mut stanza = [0u8; STANZA_SIZE];
+                stanza[..32].copy_from_slice(ephemeral_public.as_bytes());
+                stanza[32..].copy_from_slice(&wrapped);
+                Ok(stanza)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((Self { stanzas }, ChunkCipher::new(&file_key)))
+    }
+
+    /// Unwraps the file key with the first identity it was encrypted to.
+    pub fn unlock(&self, identities: &[Identity]) -> Result<ChunkCipher> {
+        for identity in identities {
+            let recipient = identity.recipient().0;
+            for stanza in &self.stanzas {
+                let ephemeral = PublicKey::from(<[u8; 32]>::try_from(&stanza[..32])?);
+                let shared = identity.0.diffie_hellman(&ephemeral);
+                let Ok(wrap) = Self::wrap_cipher(shared, &ephemeral, &recipient) else {
+                    continue;
+                };
+                let mut file_key = stanza[32..].to_vec();
+                if wrap.open(0, &mut file_key).is_ok() {
+                    return Ok(ChunkCipher::new(&file_key.try_into().expect("Unwrapped a file key")));
+                }
+            }
+        }
+        Err(anyhow::anyhow!("None of the given identities can decrypt this video"))
+    }
+
+    /// Cipher wrapping the file key for one recipient: the X25519 secret
+    /// shared between the ephemeral and the recipient key, through
+    /// HKDF-SHA256 salted with both public keys. Stanza keys are single use,
+    /// so the nonce is fixed.
+    fn wrap_cipher(shared: SharedSecret, ephemeral: &PublicKey, recipient: &PublicKey) -> Result<ChunkCipher> {
+        if !shared.was_contributory() {
+            return Err(anyhow::anyhow!("Degenerate X25519 public key"));
+        }
+        let mut salt = ephemeral.as_bytes().to_vec();
+        salt.extend_from_slice(recipient.as_bytes());
+        let mut key = [0u8; KEY_SIZE];
+        Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+            .expand(WRAP_INFO, &mut key)
+            .map_err(|_| anyhow::anyhow!("Key wrapping failed"))?;
+        Ok(ChunkCipher::new(&key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.stanzas.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![CIPHER_XCHACHA20_POLY1305, KEY_WRAP_X25519];
+        for stanza in &self.stanzas {
+            bytes.extend_from_slice(stanza);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (&[cipher, wrap], stanzas) = bytes.split_first_chunk::<2>().context("Recipients record truncated")?;
+        if cipher != CIPHER_XCHACHA20_POLY1305 || wrap != KEY_WRAP_X25519 {
+            return Err(anyhow::anyhow!("Unsupported encryption (cipher {}, key wrap {})", cipher, wrap));
+        }
+        if stanzas.is_empty() || stanzas.len() % STANZA_SIZE != 0 {
+            return Err(anyhow::anyhow!("Recipients record malformed"));
+        }
+        let stanzas = stanzas
+            .chunks_exact(STANZA_SIZE)
+            .map(|s| s.try_into().expect("Exact chunks"))
+            .collect();
+        Ok(Self { stanzas })
+    }
+}
+
+/// Reads a passphrase from the first line of a file, for unattended use.
+pub fn read_passphrase_file(path: &str) -> Result<String> {
+    let contents = std::fs::read_to_string(path).context("Failed to read passphrase file")?;
+    let passphrase = contents.lines().next().unwrap_or_default().to_string();
+    if passphrase.is_empty() {
+        return Err(anyhow::anyhow!("Passphrase file {} is empty", path));
+    }
+    Ok(passphrase)
+}
+
+/// Asks for the passphrase on the terminal, twice when `confirm` is set.
+pub fn prompt_passphrase(confirm: bool) -> Result<String> {
+    let passphrase = rpassword::prompt_password("Passphrase: ").context("Failed to read passphrase")?;
+    if passphrase.is_empty() {
+        return Err(anyhow::anyhow!("Passphrase must not be empty"));
+    }
+    if confirm && rpassword::prompt_password("Confirm passphrase: ")? != passphrase {
+        return Err(anyhow::anyhow!("Passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap enough for tests; real videos use the defaults.
+    const TEST_KDF: KdfParams = KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 };
+
+    #[test]
+    fn test_chunks_round_trip_and_authenticate() {
+        let (encryption, cipher) = Encryption::new("correct horse", TEST_KDF).unwrap();
+        let plaintext: Vec<u8> = (0..1000).map(|i| (i % 256) as u8).collect();
+        let mut chunk = plaintext.clone();
+        cipher.seal(7, &mut chunk).unwrap();
+        assert_eq!(chunk.len(), plaintext.len() + TAG_SIZE);
+        assert_ne!(chunk[..plaintext.len()], plaintext[..]);
+
+        // Through the header and back
+        let encryption = Encryption::from_bytes(&encryption.to_bytes().unwrap()).unwrap();
+        let cipher = encryption.unlock("correct horse").unwrap();
+        let mut opened = chunk.clone();
+        cipher.open(7, &mut opened).unwrap();
+        assert_eq!(opened, plaintext);
+
+        // Wrong place, a flipped bit, or the wrong passphrase
+        assert!(cipher.open(8, &mut chunk.clone()).is_err());
+        let mut tampered = chunk.clone();
+        tampered[500] ^= 0x01;
+        assert!(cipher.open(7, &mut tampered).is_err());
+        assert!(encryption.unlock("battery staple").is_err());
+    }
+
+    #[test]
+    fn test_oversized_kdf_refused() {
+        let (encryption, _) = Encryption::new("correct horse", TEST_KDF).unwrap();
+        let mut bytes = encryption.to_bytes().unwrap();
+        // Memory, iterations and parallelism follow the two algorithm bytes
+        for (offset, value) in [(2, KdfParams::MAX.memory_kib + 1), (6, 65), (10, 17), (2, u32::MAX)] {
+            let mut crafted = bytes.clone();
+            crafted[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+            assert!(Encryption::from_bytes(&crafted).is_err());
+        }
+        bytes[2..6].copy_from_slice(&KdfParams::MAX.memory_kib.to_be_bytes());
+        assert_eq!(Encryption::from_bytes(&bytes).unwrap().kdf.memory_kib, KdfParams::MAX.memory_kib);
+    }
+
+    #[test]
+    fn test_recipients_unwrap_file_key() {
+        let (alice, bob, eve) = (Identity::generate().unwrap(), Identity::generate().unwrap(), Identity::generate().unwrap());
+        let recipients = [alice.recipient(), bob.recipient()];
+        let (record, cipher) = Recipients::new(&recipients).unwrap();
+        let mut chunk = b"team archive".to_vec();
+        cipher.seal(0, &mut chunk).unwrap();
+
+        let record = Recipients::from_bytes(&record.to_bytes()).unwrap();
+        assert_eq!(record.len(), 2);
+        for identity in [&bob, &alice] {
+            let mut opened = chunk.clone();
+            record.unlock(std::slice::from_ref(identity)).unwrap().open(0, &mut opened).unwrap();
+            assert_eq!(opened, b"team archive");
+        }
+        assert!(record.unlock(std::slice::from_ref(&eve)).is_err());
+        assert!(record.unlock(&[eve, bob]).is_ok());
+
+        // Keys survive their text form
+        let key = alice.recipient().to_string();
+        assert!(key.starts_with(PUBLIC_KEY_PREFIX));
+        assert_eq!(key.parse::<Recipient>().unwrap(), alice.recipient());
+        assert!("B2V-PUBLIC-KEY-00".parse::<Recipient>().is_err());
+    }
+}
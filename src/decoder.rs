@@ -1,244 +1,1571 @@
-use crate::utils::{FileHeader, PIXEL_FORMAT, VIDEO_HEIGHT, VIDEO_WIDTH};
+use crate::utils::{
+    body_layout, bootstrap_geometry, bootstrap_layout, probe_frame_count, probe_frame_rate, probe_geometry, strip_layout, to_hex, EncodingParams,
+    FileHeader, FileTrailer, FrameHeader, Geometry, FRAME_HEADER_COPIES, PIXEL_FORMAT,
+};
+use crate::archive::ArchiveWriter;
+use crate::bootstrap;
+use crate::compression::Compression;
+use crate::crypto::{self, ChunkCipher, Identity, TAG_SIZE};
+use crate::index::{is_index_frame, Index};
+use crate::info::VideoInfo;
+use crate::scrub::{BodySample, FrameRead, ScrubReport};
+use crate::metadata::{stored_path, Restore};
+use crate::layout::Layout;
+use crate::calibration::{patch_columns, Calibration, Levels, CALIBRATION_HEIGHT, CALIBRATION_PATCHES};
+use crate::modulation::{pack_symbols, read_symbol, Modulation};
+use crate::sampling::Sampling;
+use crate::signing::{MerkleTree, SignatureStatus};
 use anyhow::{Context, Result};
+use serde::Serialize;
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use reed_solomon_erasure::galois_8::ReedSolomon;
 use sha2::{Digest, Sha256};
 use std::io::{Read, Write};
-use std::process::{Command, Stdio};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::SyncSender;
+
+/// Decoder tuning that isn't recorded in the video itself.
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub sampling: Sampling,
+    /// Shards containing a symbol read with less confidence than this (0-255)
+    /// are treated as erasures even if their CRC matches. 0 trusts the CRC alone.
+    pub min_confidence: u8,
+    /// Read every frame against the calibrated levels as they are, instead of
+    /// adapting them to each frame's strip and histogram.
+    pub fixed_levels: bool,
+    /// Passphrase for encrypted videos. Asked for on the terminal if needed and not given.
+    pub passphrase: Option<String>,
+    /// Secret keys for videos encrypted to recipients
+    pub identities: Vec<Identity>,
+    /// What of the recorded metadata to put back on written files
+    pub restore: Restore,
+}
+
+/// What decoding found out about the video, besides the data itself.
+#[derive(Debug, Serialize)]
+pub struct DecodeReport {
+    /// Only `Valid` if the chunks also add up to the signed Merkle root
+    pub signature: SignatureStatus,
+    pub hash: HashCheck,
+    pub parity_shards: usize,
+    pub chunks: Vec<ChunkReport>,
+}
+
+impl DecodeReport {
+    /// Parity shards to spare in the worst chunk that could be recovered:
+    /// how many more lost shards it would have taken to lose data.
+    pub fn min_margin(&self) -> Option<usize> {
+        self.chunks
+            .iter()
+            .filter(|c| c.status != ChunkStatus::Unrecoverable)
+            .map(|c| self.parity_shards.saturating_sub(c.erased))
+            .min()
+    }
+
+    pub fn count(&self, status: ChunkStatus) -> usize {
+        self.chunks.iter().filter(|c| c.status == status).count()
+    }
+
+    /// Fails unless the data matched its hash, or there was nothing to check it against.
+    pub fn check_hash(&self) -> Result<()> {
+        match &self.hash {
+            HashCheck::Mismatch { expected, calculated } => Err(anyhow::anyhow!(
+                "Hash mismatch! Expected {}, got {}. File is corrupt.",
+                expected,
+                calculated
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether the decoded data matched the hash the video carries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "status")]
+pub enum HashCheck {
+    Verified,
+    Mismatch { expected: String, calculated: String },
+    /// Encrypted and no key given
+    Unchecked,
+    /// No trailer or final header came through to compare with
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkStatus {
+    /// Every shard read back intact
+    Clean,
+    /// Some shards were lost, parity made up for them
+    Repaired,
+    /// Too many shards lost, or the chunk failed authentication
+    Unrecoverable,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkReport {
+    pub index: u64,
+    pub status: ChunkStatus,
+    /// Shards missing or damaged
+    pub erased: usize,
+}
+
+/// Where the decoded data goes.
+#[derive(Debug, Clone)]
+pub enum Destination {
+    /// Nowhere, the video is only read and checked
+    Verify,
+    /// This file, or this directory for an archive
+    Path(String),
+    /// The file name stored in the header, or the current directory for an
+    /// archive. An existing file isn't overwritten.
+    Stored,
+    /// Down a channel as it is decoded, archives as one stream, for `repair`.
+    /// Unrecoverable chunks are an error instead of being zero-filled.
+    Stream(SyncSender<Vec<u8>>),
+}
+
+/// Frames at the end of the video `info` looks through for the trailer,
+/// in case a host appended some after it
+const TRAILER_SEARCH_FRAMES: u64 = 4;
+/// How much of the end `info` reads when the trailer isn't where the frame
+/// count and rate say, or the count is unknown
+const TRAILER_TAIL_SECONDS: f64 = 2.0;
 
 pub struct Decoder {
     input_path: String,
-    output_path: String,
+    destination: Destination,
+    options: DecodeOptions,
 }
 
 impl Decoder {
-    pub fn new(input_path: String, output_path: String) -> Self {
+    pub fn new(input_path: String, destination: Destination, options: DecodeOptions) -> Self {
         Self {
             input_path,
-            output_path,
+            destination,
+            options,
         }
     }
 
-    pub fn run(&self) -> Result<()> {
-        // Spawn FFmpeg to read video
-        let mut child = Command::new("ffmpeg")
-            .args([
-                "-i", &self.input_path,
-                "-f", "rawvideo",
-                "-pix_fmt", PIXEL_FORMAT,
-                "-",
-            ])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null()) // Suppress logs
-            .spawn()
-            .context("Failed to spawn ffmpeg decodder")?;
+    pub fn run(&self) -> Result<DecodeReport> {
+        // Hosts may have rescaled the video, so frames come out of ffmpeg at
+        // whatever size the stream has now, not necessarily what we encoded.
+        let stream = probe_geometry(&self.input_path)?;
+        println!("Stream Resolution: {}", stream);
 
-        let mut ffmpeg_stdout = child.stdout.take().context("Failed to open ffmpeg stdout")?;
+        // Spawn FFmpeg to read video
+        let (mut child, mut ffmpeg_stdout) = self.spawn_ffmpeg(None)?;
 
         // Buffer for one frame
-        let frame_size = VIDEO_WIDTH * VIDEO_HEIGHT * 3;
-        let mut frame_buffer = vec![0u8; frame_size];
-
-        // 1. Read Header from first frame(s)
-        // We know we wrote header in the first frame.
-        if ffmpeg_stdout.read_exact(&mut frame_buffer).is_err() {
-            return Err(anyhow::anyhow!("Failed to read header frame"));
-        }
-
-        // Decode Header Frame
-        // We don't know block size yet, but the header was written with a specific block size?
-        // Wait, if block_size is configurable, we can't extract the header unless we know the block size used for the header!
-        // CRITICAL FLAW in plan: Header must be encoded with a FIXED, KNOWN block size (e.g. 1 or 4) OR we must guess.
-        // Let's assume for the "Standardized Bootstrap" that Frame 0 is ALWAYS encoded with Block Size = 4 (to be safe) or Block Size = 1.
-        // Let's try Block Size = 4 as a safe default for headers.
-        // REVISIT Encoder: Did I force block size for header? No, I used `self.block_size`.
-        // This means the user MUST know the block size to decode. 
-        // OR we can try to detect it.
-        // For this MVP, let's assume the user passes the same --block-size or we fix the header block size.
-        // The user prompt said: "Header ... custom header".
-        // Let's assume we decode with a separate logic for the header.
-        // Let's try to decode the first frame with various block sizes? No, that's slow/complex.
-        // Let's look at `utils.rs`: `block_size` is IN the header.
-        // So we must be able to read the header WITHOUT knowing the block size.
-        // This implies Header should use a FIXED block size (e.g. 1 or 4) so we can always read it.
-        // Implementation Detail: Encoder must use fixed block size for header.
-        // Let's update this Logic.
-        // For now, I will assume the user provides the block size to the decoder via CLI? 
-        // The prompt says "Legge un video... salva il file originale". It doesn't explicitly say we must auto-detect params, but it's implied by "Header contains... Dimensione File".
-        // Let's USE A FIXED BLOCK SIZE OF 1 for the Header Frame to ensure maximum readability, or maybe 4 for safety against compression?
-        // Let's assume Block Size 4 for Header.
-        
-        let header_block_size = 4;
-        let header_bytes = self.decode_frame_to_bytes(&frame_buffer, header_block_size)?;
-        
-        // Try to parse header
-        let header = FileHeader::from_bytes(&header_bytes).context("Failed to parse header. Is this a compatible video?")?;
-        
+        let mut frame_buffer = vec![0u8; stream.frame_size()];
+
+        // 1. Find the header. We wrote it in the first frame, but copies
+        // further in keep us going if the start is damaged or trimmed.
+        // The bootstrap grid is defined relative to the frame, so it can be
+        // read without knowing the block size or the original resolution.
+        let (header, calibration, header_frame) = self.find_header(&mut ffmpeg_stdout, &mut frame_buffer, stream)?;
+        if header_frame > 0 {
+            // Whatever came before the copy is still worth decoding, now that we know how
+            println!("Frame 0 holds no readable header, using the copy in frame {}", header_frame);
+            let _ = child.kill();
+            let _ = child.wait();
+            (child, ffmpeg_stdout) = self.spawn_ffmpeg(None)?;
+        }
+        let (black, white) = calibration.range(0);
+        println!("Calibration: black {:.0}, white {:.0}", black, white);
+
         println!("Found Header:");
+        println!("  Format Version: {}.{}", header.version, header.minor_version);
         println!("  Original Filename: {}", header.original_filename);
         println!("  File Size: {}", header.file_size);
-        println!("  Block Size: {}", header.block_size);
+        println!("  Block Size: {}", header.block_size());
+        println!("  Bits per Block: {}{}", header.bits_per_block, if header.color { " per channel (colour)" } else { "" });
         println!("  Data Shards: {}", header.data_shards);
         println!("  Parity Shards: {}", header.parity_shards);
         println!("  Original Hash: {}", header.sha256_hash);
+        println!("  Resolution: {} @ {} fps", header.geometry(), header.fps);
 
-        // Prepare for processing body
-        let mut output_file = std::fs::File::create(&self.output_path)?;
-        let mut hasher = Sha256::new();
-        let pb = ProgressBar::new(header.file_size);
+        // Just verifying doesn't need the plaintext: without a key, the
+        // chunks can still be checked against a signed Merkle root
+        let is_archive = header.is_archive()?;
+        let output_path = match &self.destination {
+            Destination::Verify | Destination::Stream(_) => None,
+            Destination::Path(path) => Some(PathBuf::from(path)),
+            Destination::Stored if is_archive => Some(PathBuf::from(".")),
+            Destination::Stored => Some(stored_path(&header.original_filename)?),
+        };
+        let streaming = matches!(self.destination, Destination::Stream(_));
+        let unlock = output_path.is_some() || streaming || self.options.passphrase.is_some() || !self.options.identities.is_empty();
+        let cipher = self.unlock(&header, unlock)?;
+
+        let compression = header.compression()?;
+        if let Some(compression) = compression {
+            println!("  Compression: zstd level {}", compression.level);
+        }
+
+        // Everything below is driven by the header, not by our own defaults
+        let params = header.params()?;
+        params.validate().context("Header contains invalid encoding parameters")?;
+        let encoded = params.geometry;
+        if encoded != stream {
+            println!("Video was rescaled from {} to {}, resampling block centres", encoded, stream);
+        }
+
+        // Prepare for processing body. Whatever a stream goes into shows its own progress.
+        let pb = match streaming {
+            true => ProgressBar::hidden(),
+            false => ProgressBar::new(header.file_size),
+        };
         pb.set_style(ProgressStyle::default_bar()
             .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")?
             .progress_chars("#>-"));
+        // Without the key we only see ciphertext, which the hash doesn't cover
+        let plaintext = cipher.is_some() || !header.is_encrypted();
+        // An archive goes into a directory, its files are checked even when just verifying
+        let sink = match (&output_path, is_archive) {
+            _ if streaming => match &self.destination {
+                Destination::Stream(sender) => Sink::Stream(sender.clone()),
+                _ => unreachable!(),
+            },
+            (output, true) if plaintext => {
+                // Like a single file, names that came with the video don't get to replace anything
+                let no_clobber = matches!(self.destination, Destination::Stored);
+                Sink::Archive(Box::new(ArchiveWriter::new(output.clone(), self.options.restore, no_clobber)?))
+            },
+            (Some(path), false) => {
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create(true);
+                // A name that came with the video doesn't get to replace anything
+                match self.destination {
+                    Destination::Stored => options.create_new(true),
+                    _ => options.truncate(true),
+                };
+                println!("Writing to {}", path.display());
+                Sink::File(options.open(path).with_context(|| format!("Failed to create {}", path.display()))?)
+            }
+            _ => Sink::Discard,
+        };
+        let mut output = Output {
+            sink,
+            hasher: Sha256::new(),
+            written: 0,
+            progress: pb.clone(),
+        };
+        // Without the key, compressed chunks are only checked as stored
+        let unpack = compression.is_some() && plaintext;
+        let mut merkle_tree = MerkleTree::default();
 
-        let rs = ReedSolomon::new(header.data_shards, header.parity_shards)?;
-        let total_shards = header.data_shards + header.parity_shards;
-        
-        // Compute frame capacity (shard size)
-        // let cols = VIDEO_WIDTH / header.block_size as usize; // unused
-        // let rows = VIDEO_HEIGHT / header.block_size as usize; // unused
-        // let bytes_per_frame = (cols * rows) / 8; // unused
-        
-        let mut shards_buffer: Vec<Option<Vec<u8>>> = vec![None; total_shards];
-        let mut shards_received = 0;
-        let mut bytes_written_total = 0u64;
+        let payload_size = header.payload_size()? as u64;
+        // How many chunks a compressed input took only comes with the trailer,
+        // until then any chunk up to the most it could take is accepted
+        let mut expected_chunks = match compression {
+            Some(_) => Compression::max_chunks(header.file_size, payload_size as usize),
+            None => header.file_size.div_ceil(payload_size),
+        };
+        let total_shards = params.total_shards();
 
-        loop {
-            // Read next frame
-            // Note: We might have read one frame for header. The loop starts from frame 1.
-            // But we need to handle the loop carefully.
-            // We need to read `total_shards` frames to reconstruct one chunk.
-            
-            // Actually, we read continuously.
-            if shards_received == total_shards {
-                // Should not happen if we process logic below correctly
-                shards_received = 0;
-                shards_buffer.fill(None);
+        let mut assembler = ChunkAssembler::new(params.data_shards, params.parity_shards)?;
+        let mut trailer = None;
+        // Header copies written at the end of the video carry the real hash
+        let mut header_hash = (header.sha256_hash != "PENDING").then(|| header.sha256_hash.clone());
+
+        // Frame accounting, from the frame numbers in the strips
+        let mut last_frame_number = 0u32; // Frame 0 is the header
+        let mut dropped_frames = 0u64;
+        let mut duplicate_frames = 0u64;
+        let mut unreadable_frames = 0u64;
+        let mut low_confidence_shards = 0u64;
+        let mut failed_chunks = 0u64;
+        let mut chunk_reports = Vec::new();
+
+        // Writes one chunk to the output. Lost chunks are zero-filled so the
+        // following chunks still land at the right offset, unless streaming.
+        let mut write_chunk = |chunk: DecodedChunk| -> Result<()> {
+            // Compressed chunks are always padded to full size
+            let len = match compression {
+                Some(_) => payload_size as usize,
+                None => payload_size.min(header.file_size - output.written) as usize,
+            };
+            merkle_tree.push(chunk.data.as_deref());
+            let mut status = match (&chunk.data, chunk.erased) {
+                (None, _) => ChunkStatus::Unrecoverable,
+                (Some(_), 0) => ChunkStatus::Clean,
+                (Some(_), _) => ChunkStatus::Repaired,
+            };
+            let data = match chunk.data {
+                Some(mut data) => {
+                    if chunk.erased > 0 {
+                        println!(
+                            "Chunk {}: repaired {} of {} shards",
+                            chunk.index, chunk.erased, total_shards
+                        );
+                    }
+                    if let Some(cipher) = &cipher {
+                        data.truncate(len + TAG_SIZE);
+                        if cipher.open(chunk.index, &mut data).is_err() {
+                            eprintln!("Chunk {}: authentication failed, damaged or tampered with", chunk.index);
+                            failed_chunks += 1;
+                            status = ChunkStatus::Unrecoverable;
+                            data = vec![0u8; len];
+                        }
+                    }
+                    data
+                }
+                None => {
+                    eprintln!(
+                        "Chunk {}: unrecoverable ({} of {} shards missing or damaged)",
+                        chunk.index, chunk.erased, total_shards
+                    );
+                    vec![0u8; len]
+                }
+            };
+            let to_write = &data[..len.min(data.len())];
+            // A compressed chunk is a zstd frame of its own, so one lost
+            // before it doesn't stop it being read
+            let unpacked = match status {
+                _ if !unpack => None,
+                ChunkStatus::Unrecoverable => None,
+                _ => match Compression::unpack(to_write, header.file_size) {
+                    Some((offset, data)) if offset >= output.written => Some((offset, data)),
+                    _ => {
+                        eprintln!("Chunk {}: compressed data is damaged", chunk.index);
+                        status = ChunkStatus::Unrecoverable;
+                        None
+                    }
+                },
+            };
+            chunk_reports.push(ChunkReport { index: chunk.index, status, erased: chunk.erased });
+            if streaming && status == ChunkStatus::Unrecoverable {
+                return Err(anyhow::anyhow!("Chunk {} is lost, the data can't be passed on whole", chunk.index));
             }
+            match unpacked {
+                Some((offset, data)) => {
+                    // It says where its data goes: whatever chunks were lost before it are zero-filled
+                    std::io::copy(&mut std::io::repeat(0).take(offset - output.written), &mut output)?;
+                    output.write_all(&data)?;
+                }
+                None if unpack => {}
+                None => output.write_all(to_write)?,
+            }
+            Ok(())
+        };
 
+        loop {
             match ffmpeg_stdout.read_exact(&mut frame_buffer) {
                 Ok(_) => {},
                 Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
                 Err(e) => return Err(e.into()),
             }
 
-            let shard_data = self.decode_frame_to_bytes(&frame_buffer, header.block_size as usize)?;
-            
-            // In a real stream, we'd need to know WHICH shard this is (index).
-            // Current Encoder writes them in order: Data 0, Data 1... Parity 0...
-            // So we can assume strict ordering for V1.
-            // In V2, we might embed a "Frame Header" with shard index.
-            // For now, strictly sequential.
-            
-            shards_buffer[shards_received] = Some(shard_data);
-            shards_received += 1;
-
-            if shards_received == total_shards {
-                // Reconstruct
-                // shards_buffer is Vec<Option<Vec<u8>>>, which is what reconstruct expects
-                match rs.reconstruct(&mut shards_buffer) {
-                    Ok(_) => {
-                        // Write Data Shards
-                        for data in shards_buffer.iter().take(header.data_shards) {
-                            if let Some(data) = data {
-                                // Handle last chunk size
-                                let mut to_write = data.as_slice();
-                                let remaining = header.file_size - bytes_written_total;
-                                if remaining < to_write.len() as u64 {
-                                    to_write = &to_write[..remaining as usize];
-                                }
-                                
-                                if !to_write.is_empty() {
-                                    output_file.write_all(to_write)?;
-                                    hasher.update(to_write);
-                                    pb.inc(to_write.len() as u64);
-                                    bytes_written_total += to_write.len() as u64;
-                                }
-                            } else {
-                                // This should strictly not happen if reconstruct succeeded
-                                eprintln!("Unexpected missing data shard after reconstruction");
-                            }
+            let frame = ScaledFrame::new(&frame_buffer, stream, encoded);
+            // A strip that reads as a header pointing outside the video is a
+            // fluke, most likely the calibration band of the trailer.
+            let frame_header = self
+                .decode_frame_header(&frame, &calibration)
+                .filter(|h| (h.chunk_index as u64) < expected_chunks && (h.shard_index as usize) < total_shards);
+            let Some(frame_header) = frame_header else {
+                // No readable strip: the trailer, a header copy, or a frame too damaged to place.
+                match self.decode_bootstrap(&frame_buffer, stream, &calibration) {
+                    Ok(bytes) => {
+                        if let Ok(t) = FileTrailer::from_bytes(&bytes) {
+                            trailer = Some(t);
+                            break;
+                        }
+                        match FileHeader::from_bytes(&bytes) {
+                            Ok(copy) if copy.sha256_hash != "PENDING" => header_hash = Some(copy.sha256_hash),
+                            Ok(_) => {}
+                            // Only `extract` needs the index
+                            Err(_) if is_index_frame(&bytes) => {}
+                            Err(_) => unreadable_frames += 1,
                         }
                     }
-                    Err(e) => eprintln!("RS Reconstruction failed for chunk: {:?}", e),
+                    Err(_) => unreadable_frames += 1,
                 }
+                continue;
+            };
 
-                shards_received = 0;
-                shards_buffer.fill(None);
-                
-                if bytes_written_total >= header.file_size {
-                    break;
-                }
+            // Platforms re-encoding the video may drop or repeat frames. The
+            // frame number tells us which, the chunk/shard index where it goes.
+            if frame_header.frame_number <= last_frame_number {
+                duplicate_frames += 1;
+                continue;
+            }
+            dropped_frames += (frame_header.frame_number - last_frame_number - 1) as u64;
+            last_frame_number = frame_header.frame_number;
+
+            let chunk_index = frame_header.chunk_index as u64;
+            if chunk_index < assembler.chunk_index || chunk_index >= expected_chunks {
+                continue;
+            }
+            // First frame of a later chunk: whatever we have of the current
+            // one is all we will get, missing shards are erasures.
+            while assembler.chunk_index < chunk_index {
+                write_chunk(assembler.finish())?;
+            }
+
+            match self.read_shard(&frame, frame_header, &calibration, &params)? {
+                ShardRead::Verified(payload) => assembler.insert(frame_header.shard_index as usize, payload),
+                ShardRead::Weak => low_confidence_shards += 1,
+                ShardRead::Damaged => {}
             }
+
+            if assembler.is_complete() {
+                write_chunk(assembler.finish())?;
+            }
+        }
+
+        // Done reading, whether or not ffmpeg has more for us
+        drop(ffmpeg_stdout);
+        let _ = child.kill();
+        let _ = child.wait();
+
+        // Now we know how many chunks a compressed stream has. Without the
+        // trailer, take what we got.
+        if compression.is_some() {
+            expected_chunks = match &trailer {
+                Some(t) => t.total_chunks,
+                None => assembler.chunk_index + !assembler.is_empty() as u64,
+            };
+        }
+
+        // Flush the chunk in progress and account for any chunks that never showed up
+        while assembler.chunk_index < expected_chunks {
+            write_chunk(assembler.finish())?;
+        }
+        // Compressed chunks lost at the end leave no later chunk to say how much they held
+        if unpack && output.written < header.file_size {
+            if streaming {
+                return Err(anyhow::anyhow!("Compressed data ends early, the data can't be passed on whole"));
+            }
+            if chunk_reports.iter().all(|c| c.status != ChunkStatus::Unrecoverable) {
+                eprintln!("WARNING: Compressed stream ends early, the end of the file is missing");
+            }
+            std::io::copy(&mut std::io::repeat(0).take(header.file_size - output.written), &mut output)?;
         }
 
         pb.finish_with_message("Decoding complete");
-        
-        let calculated_hash = format!("{:x}", hasher.finalize());
+        match std::mem::replace(&mut output.sink, Sink::Discard) {
+            Sink::Archive(archive) => {
+                let damaged = archive.finish()?;
+                for path in &damaged {
+                    eprintln!("File {}: damaged or missing", path);
+                }
+            }
+            Sink::File(file) => {
+                drop(file);
+                if let (Some(path), Some(metadata)) = (&output_path, header.metadata()?) {
+                    metadata.apply(path, &self.options.restore)?;
+                }
+            }
+            Sink::Stream(_) | Sink::Discard => {}
+        }
+
+        if dropped_frames > 0 || duplicate_frames > 0 || unreadable_frames > 0 {
+            println!(
+                "Frames: {} dropped, {} duplicated, {} unreadable",
+                dropped_frames, duplicate_frames, unreadable_frames
+            );
+        }
+        if low_confidence_shards > 0 {
+            println!("Shards erased for low confidence: {}", low_confidence_shards);
+        }
+        if failed_chunks > 0 {
+            eprintln!("Chunks failing authentication: {}", failed_chunks);
+        }
+
+        let signature = match &trailer {
+            Some(t) => self.check_signature(&header, t, &merkle_tree),
+            None => SignatureStatus::Unsigned,
+        };
+        let mut report = DecodeReport {
+            signature,
+            hash: HashCheck::Unknown,
+            parity_shards: params.parity_shards,
+            chunks: chunk_reports,
+        };
+        println!(
+            "Chunks: {} clean, {} repaired, {} unrecoverable",
+            report.count(ChunkStatus::Clean),
+            report.count(ChunkStatus::Repaired),
+            report.count(ChunkStatus::Unrecoverable)
+        );
+        if let Some(margin) = report.min_margin() {
+            println!("Parity margin: {} of {} parity shards to spare in the worst chunk", margin, params.parity_shards);
+        }
+
+        if !plaintext {
+            println!("Encrypted and no key given, the content hash was not checked.");
+            report.hash = HashCheck::Unchecked;
+            return Ok(report);
+        }
+
+        let calculated_hash = format!("{:x}", output.hasher.finalize());
         println!("Calculated Hash: {}", calculated_hash);
-        if header.sha256_hash != "PENDING" && header.sha256_hash != calculated_hash {
-             println!("WARNING: Hash mismatch! File might be corrupt.");
-             println!("Header Hash: {}", header.sha256_hash);
-        } else {
-             println!("File recovered successfully.");
+
+        let expected_hash = match &trailer {
+            Some(t) => {
+                println!("Trailer Hash: {}", t.sha256_hash);
+                if t.total_chunks != expected_chunks || t.byte_length != output.written {
+                    println!(
+                        "WARNING: Trailer expects {} chunks / {} bytes, decoded {} chunks / {} bytes",
+                        t.total_chunks, t.byte_length, expected_chunks, output.written
+                    );
+                }
+                Some(t.sha256_hash.clone())
+            }
+            None => header_hash,
+        };
+
+        report.hash = match expected_hash {
+            Some(expected) if expected != calculated_hash => HashCheck::Mismatch { expected, calculated: calculated_hash },
+            Some(_) => {
+                println!("File recovered successfully. Hash verified.");
+                HashCheck::Verified
+            }
+            None => {
+                println!("WARNING: No trailer found, integrity could not be verified.");
+                HashCheck::Unknown
+            }
+        };
+
+        Ok(report)
+    }
+
+    /// Extracts one file of an archive into the output path, reading only
+    /// the frames its index entry points to.
+    pub fn extract(&self, path: &str) -> Result<()> {
+        let Destination::Path(output_path) = &self.destination else {
+            return Err(anyhow::anyhow!("No output path given"));
+        };
+        let stream = probe_geometry(&self.input_path)?;
+        let mut frame_buffer = vec![0u8; stream.frame_size()];
+
+        // The index frames come right after the first header
+        let (mut child, mut ffmpeg_stdout) = self.spawn_ffmpeg(None)?;
+        let (header, calibration, header_frame) = self.find_header(&mut ffmpeg_stdout, &mut frame_buffer, stream)?;
+        let Some(index_frames) = header.index_frames()? else {
+            return Err(anyhow::anyhow!("This video has no index, only uncompressed archives do. Use decode instead."));
+        };
+        if header_frame > 0 {
+            return Err(anyhow::anyhow!("Frame 0 holds no readable header, so the index is lost. Use decode instead."));
+        }
+        let cipher = self.unlock(&header, true)?;
+        let mut payloads = Vec::new();
+        for _ in 0..index_frames {
+            ffmpeg_stdout.read_exact(&mut frame_buffer).context("Video ends inside the index")?;
+            payloads.push(self.decode_bootstrap(&frame_buffer, stream, &calibration)?);
         }
+        drop(ffmpeg_stdout);
+        let _ = child.kill();
+        let _ = child.wait();
+        let index = Index::from_frames(&payloads, cipher.as_ref()).context("Index is damaged, use decode instead")?;
+        let entry = index.find(path).with_context(|| format!("No file {} in the archive", path))?;
 
+        let params = header.params()?;
+        params.validate().context("Header contains invalid encoding parameters")?;
+        let payload_size = header.payload_size()? as u64;
+        let total_shards = params.total_shards();
+        println!(
+            "Extracting {} ({} bytes, chunks {} to {}, frames {} to {})",
+            entry.path, entry.size, entry.first_chunk, entry.last_chunk, entry.first_frame, entry.last_frame
+        );
+
+        let mut file = std::fs::File::create(output_path)?;
+        let mut hasher = Sha256::new();
+        if entry.size > 0 {
+            // A chunk's worth of frames either side, in case a host dropped or repeated some
+            let margin = total_shards as u64;
+            let frames = entry.first_frame.saturating_sub(margin)..entry.last_frame + margin + 1;
+            let (mut child, mut ffmpeg_stdout) = self.spawn_ffmpeg(Some(Seek::Frames { frames, fps: self.frame_rate(&header) }))?;
+            let mut assembler = ChunkAssembler::new(params.data_shards, params.parity_shards)?;
+            assembler.chunk_index = entry.first_chunk;
+
+            // Writes this file's part of one chunk
+            let mut write_chunk = |chunk: DecodedChunk| -> Result<()> {
+                let Some(mut data) = chunk.data else {
+                    return Err(anyhow::anyhow!(
+                        "Chunk {}: unrecoverable ({} of {} shards missing or damaged)",
+                        chunk.index, chunk.erased, total_shards
+                    ));
+                };
+                let start = chunk.index * payload_size;
+                let len = payload_size.min(header.file_size - start);
+                if let Some(cipher) = &cipher {
+                    data.truncate(len as usize + TAG_SIZE);
+                    cipher.open(chunk.index, &mut data)?;
+                }
+                let from = entry.offset.max(start) - start;
+                let to = (entry.offset + entry.size).min(start + len) - start;
+                let part = &data[from as usize..to as usize];
+                file.write_all(part)?;
+                hasher.update(part);
+                Ok(())
+            };
+
+            while assembler.chunk_index <= entry.last_chunk {
+                match ffmpeg_stdout.read_exact(&mut frame_buffer) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e.into()),
+                }
+                let frame = ScaledFrame::new(&frame_buffer, stream, params.geometry);
+                let frame_header = self
+                    .decode_frame_header(&frame, &calibration)
+                    .filter(|h| (h.shard_index as usize) < total_shards);
+                let Some(frame_header) = frame_header else {
+                    continue;
+                };
+                let chunk_index = frame_header.chunk_index as u64;
+                if chunk_index < assembler.chunk_index || chunk_index > entry.last_chunk {
+                    continue;
+                }
+                while assembler.chunk_index < chunk_index {
+                    write_chunk(assembler.finish())?;
+                }
+                if let ShardRead::Verified(payload) = self.read_shard(&frame, frame_header, &calibration, &params)? {
+                    assembler.insert(frame_header.shard_index as usize, payload);
+                }
+                if assembler.is_complete() {
+                    write_chunk(assembler.finish())?;
+                }
+            }
+            drop(ffmpeg_stdout);
+            let _ = child.kill();
+            let _ = child.wait();
+            while assembler.chunk_index <= entry.last_chunk {
+                write_chunk(assembler.finish())?;
+            }
+        }
+
+        let calculated_hash = hasher.finalize();
+        if calculated_hash[..] != entry.sha256 {
+            return Err(anyhow::anyhow!(
+                "Hash mismatch! Expected {}, got {}. File is corrupt.",
+                to_hex(&entry.sha256),
+                to_hex(&calculated_hash)
+            ));
+        }
+        println!("File extracted successfully. Hash verified.");
         Ok(())
     }
 
-    fn decode_frame_to_bytes(&self, frame: &[u8], block_size: usize) -> Result<Vec<u8>> {
-        let cols = VIDEO_WIDTH / block_size;
-        let rows = VIDEO_HEIGHT / block_size;
-        let max_bytes = (cols * rows) / 8;
-        
-        // let mut bytes = vec![0u8; max_bytes]; // unused
-        
-        // Parallel bit extraction? 
-        // For decoding, we need strict ordering, so maybe just standard iter is fast enough?
-        // Let's use rayon for rows to speed up.
-        
-        // We need to write into 'bytes' which is flat.
-        // It's a bit harder to parallelize writing to a bitpacked vec safely without mutex or nice indexing.
-        // But we can map rows to "partial bytes" and then join?
-        // Or just process sequentially for V1 MVP since 1080p is small-ish.
-        // Actually, 1920x1080 is 2M pixels. Sequential loop might be slowish but okay-ish (100ms?).
-        // For "Enterprise", let's parallelize.
-        
-        // We collect bits/bytes per row then merge.
-        let bytes_per_row = cols / 8;
-        
-        let row_results: Vec<Vec<u8>> = (0..rows).into_par_iter().map(|row_idx| {
-            let mut row_bytes = vec![0u8; bytes_per_row];
-            for bx in 0..cols {
-                // Sample pixel
-                // Where to sample? Center of block is safest.
-                let center_x = bx * block_size + block_size / 2;
-                let center_y = row_idx * block_size + block_size / 2;
-                
-                let px_idx = (center_y * VIDEO_WIDTH + center_x) * 3;
-                
-                // Simple threshold: > 128 is 1, else 0
-                // We use Red channel [0]
-                if px_idx < frame.len() {
-                    let val = frame[px_idx];
-                    if val > 126 { // Threshold
-                         let byte_idx = bx / 8;
-                         let bit_offset = 7 - (bx % 8);
-                         if byte_idx < row_bytes.len() {
-                             row_bytes[byte_idx] |= 1 << bit_offset;
-                         }
-                    }
+    /// Reads the first readable header of the video, and nothing else.
+    pub fn header(&self) -> Result<FileHeader> {
+        let stream = probe_geometry(&self.input_path)?;
+        let mut frame_buffer = vec![0u8; stream.frame_size()];
+        let (mut child, mut ffmpeg_stdout) = self.spawn_ffmpeg(None)?;
+        let (header, _, _) = self.find_header(&mut ffmpeg_stdout, &mut frame_buffer, stream)?;
+        drop(ffmpeg_stdout);
+        let _ = child.kill();
+        let _ = child.wait();
+        Ok(header)
+    }
+
+    /// Reads the header at the start of the video and the trailer at its end,
+    /// without decoding anything in between.
+    pub fn info(&self) -> Result<VideoInfo> {
+        let stream = probe_geometry(&self.input_path)?;
+        let present_frames = probe_frame_count(&self.input_path).ok();
+        let mut frame_buffer = vec![0u8; stream.frame_size()];
+
+        let (mut child, mut ffmpeg_stdout) = self.spawn_ffmpeg(None)?;
+        let (header, calibration, _) = self.find_header(&mut ffmpeg_stdout, &mut frame_buffer, stream)?;
+        drop(ffmpeg_stdout);
+        let _ = child.kill();
+        let _ = child.wait();
+
+        let mut tail = (None, None);
+        if let Some(count) = present_frames {
+            let frames = count.saturating_sub(TRAILER_SEARCH_FRAMES)..count;
+            let fps = self.frame_rate(&header);
+            tail = self.read_tail(Seek::Frames { frames, fps }, stream, &calibration, &mut frame_buffer)?;
+        }
+        if tail.0.is_none() {
+            let (trailer, hash) = self.read_tail(Seek::Tail(TRAILER_TAIL_SECONDS), stream, &calibration, &mut frame_buffer)?;
+            tail = (trailer, hash.or(tail.1));
+        }
+        let (trailer, final_hash) = tail;
+        let final_hash = final_hash.or((header.sha256_hash != "PENDING").then(|| header.sha256_hash.clone()));
+        VideoInfo::new(&header, final_hash, trailer.as_ref(), present_frames)
+    }
+
+    /// Looks through the frames of `seek` for the trailer and a final header
+    /// copy, returning the trailer and the hash that copy carries.
+    fn read_tail(
+        &self,
+        seek: Seek,
+        stream: Geometry,
+        calibration: &Calibration,
+        frame_buffer: &mut [u8],
+    ) -> Result<(Option<FileTrailer>, Option<String>)> {
+        let (mut trailer, mut final_hash) = (None, None);
+        let (mut child, mut ffmpeg_stdout) = self.spawn_ffmpeg(Some(seek))?;
+        while ffmpeg_stdout.read_exact(frame_buffer).is_ok() {
+            let Ok(bytes) = self.decode_bootstrap(frame_buffer, stream, calibration) else {
+                continue;
+            };
+            if let Ok(t) = FileTrailer::from_bytes(&bytes) {
+                trailer = Some(t);
+            } else if let Ok(copy) = FileHeader::from_bytes(&bytes) {
+                if copy.sha256_hash != "PENDING" {
+                    final_hash = Some(copy.sha256_hash);
+                }
+            }
+        }
+        drop(ffmpeg_stdout);
+        let _ = child.kill();
+        let _ = child.wait();
+        Ok((trailer, final_hash))
+    }
+
+    /// Reads every data frame and measures how far each was from being
+    /// misread, against the shards its chunk is rebuilt to. Needs no key:
+    /// the shards are checked as stored.
+    pub fn scrub(&self) -> Result<ScrubReport> {
+        let stream = probe_geometry(&self.input_path)?;
+        let mut frame_buffer = vec![0u8; stream.frame_size()];
+        let (mut child, mut ffmpeg_stdout) = self.spawn_ffmpeg(None)?;
+        let (header, calibration, header_frame) = self.find_header(&mut ffmpeg_stdout, &mut frame_buffer, stream)?;
+        if header_frame > 0 {
+            let _ = child.kill();
+            let _ = child.wait();
+            (child, ffmpeg_stdout) = self.spawn_ffmpeg(None)?;
+        }
+        let params = header.params()?;
+        params.validate().context("Header contains invalid encoding parameters")?;
+        let total_shards = params.total_shards();
+        let shard_size = params.shard_size();
+        let payload_size = header.payload_size()? as u64;
+        let expected_chunks = match header.compression()? {
+            Some(_) => Compression::max_chunks(header.file_size, payload_size as usize),
+            None => header.file_size.div_ceil(payload_size),
+        };
+
+        let rs = ReedSolomon::new(params.data_shards, params.parity_shards)?;
+        let mut assembler = ChunkAssembler::new(params.data_shards, params.parity_shards)?;
+        let mut report = ScrubReport::new(&params, &body_layout(params.geometry, params.block_size));
+        // Samples of the chunk being assembled, measured once it is rebuilt
+        let mut pending: Vec<BodySample> = Vec::new();
+        let mut last_frame_number = 0u32;
+        let mut unplaced_frames = 0u64;
+
+        let mut measure_chunk = |chunk: DecodedChunk, pending: &mut Vec<BodySample>| -> Result<()> {
+            let Some(data) = chunk.data else {
+                report.unmeasured_frames += pending.len() as u64;
+                pending.clear();
+                return Ok(());
+            };
+            // Parity as the encoder computed it, from the rebuilt data shards
+            let mut shards: Vec<Vec<u8>> = data.chunks(shard_size).map(<[u8]>::to_vec).collect();
+            shards.resize(total_shards, vec![0u8; shard_size]);
+            rs.encode(&mut shards)?;
+            for sample in pending.drain(..) {
+                let shard = sample.shard;
+                report.add(sample, &shards[shard], params.modulation);
+            }
+            Ok(())
+        };
+
+        loop {
+            match ffmpeg_stdout.read_exact(&mut frame_buffer) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let frame = ScaledFrame::new(&frame_buffer, stream, params.geometry);
+            let frame_header = self
+                .decode_frame_header(&frame, &calibration)
+                .filter(|h| (h.chunk_index as u64) < expected_chunks && (h.shard_index as usize) < total_shards);
+            let Some(frame_header) = frame_header else {
+                match self.decode_bootstrap(&frame_buffer, stream, &calibration) {
+                    Ok(bytes) if FileTrailer::from_bytes(&bytes).is_ok() => break,
+                    Ok(_) => {}
+                    Err(_) => unplaced_frames += 1,
+                }
+                continue;
+            };
+            let chunk_index = frame_header.chunk_index as u64;
+            if frame_header.frame_number <= last_frame_number || chunk_index < assembler.chunk_index {
+                continue;
+            }
+            last_frame_number = frame_header.frame_number;
+            while assembler.chunk_index < chunk_index {
+                measure_chunk(assembler.finish(), &mut pending)?;
+            }
+
+            let (shard, band) = self.read_body(&frame, frame_header, &calibration, &params, true)?;
+            let read = match shard {
+                ShardRead::Verified(payload) => {
+                    assembler.insert(frame_header.shard_index as usize, payload);
+                    FrameRead::Intact
+                }
+                ShardRead::Weak => FrameRead::Weak,
+                ShardRead::Damaged => FrameRead::Damaged,
+            };
+            let BandSamples { symbols, values, levels } = band.samples.context("Body read without its samples")?;
+            pending.push(BodySample {
+                frame_number: frame_header.frame_number,
+                chunk: chunk_index,
+                shard: frame_header.shard_index as usize,
+                read,
+                symbols,
+                values,
+                levels,
+            });
+        }
+        drop(ffmpeg_stdout);
+        let _ = child.kill();
+        let _ = child.wait();
+        if !assembler.is_empty() {
+            measure_chunk(assembler.finish(), &mut pending)?;
+        }
+
+        report.unmeasured_frames += unplaced_frames;
+        report.finish();
+        Ok(report)
+    }
+
+    /// Gets the key of an encrypted video, printing how it is encrypted.
+    /// Without `unlock`, it asks for nothing and returns `None`.
+    fn unlock(&self, header: &FileHeader, unlock: bool) -> Result<Option<ChunkCipher>> {
+        if let Some(encryption) = header.encryption()? {
+            println!(
+                "  Encryption: XChaCha20-Poly1305, Argon2id ({} MiB, {} passes)",
+                encryption.kdf.memory_kib / 1024,
+                encryption.kdf.iterations
+            );
+            let passphrase = match &self.options.passphrase {
+                Some(passphrase) => Some(passphrase.clone()),
+                None if unlock => Some(crypto::prompt_passphrase(false)?),
+                None => None,
+            };
+            return passphrase.map(|passphrase| encryption.unlock(&passphrase)).transpose();
+        }
+        match header.recipients()? {
+            Some(recipients) => {
+                println!("  Encryption: XChaCha20-Poly1305, to {} recipient(s)", recipients.len());
+                if !unlock {
+                    Ok(None)
+                } else if self.options.identities.is_empty() {
+                    Err(anyhow::anyhow!("This video is encrypted to public keys, pass --identity"))
+                } else {
+                    Ok(Some(recipients.unlock(&self.options.identities)?))
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the trailer's signature, and that the chunks we read are the
+    /// ones it signed. Prints what it finds, trusting the key is up to the caller.
+    fn check_signature(&self, header: &FileHeader, trailer: &FileTrailer, merkle_tree: &MerkleTree) -> SignatureStatus {
+        let status = SignatureStatus::check(header, trailer);
+        match &status {
+            SignatureStatus::Unsigned => println!("Signature: none"),
+            SignatureStatus::Invalid => eprintln!("WARNING: Signature is invalid, the header or trailer was altered"),
+            SignatureStatus::Valid(signer) => match merkle_tree.root() {
+                Some(root) if Some(&root) == trailer.merkle_root.as_ref() => {
+                    println!("Signature: valid, signed by {}", signer);
+                }
+                Some(_) => {
+                    eprintln!("WARNING: Signature is invalid, the chunks don't match the signed Merkle root");
+                    return SignatureStatus::Invalid;
+                }
+                None => {
+                    eprintln!("WARNING: Signature can't be checked, some chunks are unrecoverable");
+                    return SignatureStatus::Invalid;
                 }
+            },
+        }
+        status
+    }
+
+    /// The frame rate to seek by: the stream's own, since a host may have
+    /// re-encoded it at another than the header's.
+    fn frame_rate(&self, header: &FileHeader) -> f64 {
+        probe_frame_rate(&self.input_path).unwrap_or(header.fps as f64)
+    }
+
+    /// Spawns ffmpeg decoding the video to raw frames on its stdout, all of
+    /// them or just the frames of `seek`.
+    fn spawn_ffmpeg(&self, seek: Option<Seek>) -> Result<(Child, ChildStdout)> {
+        let mut command = Command::new("ffmpeg");
+        match &seek {
+            Some(Seek::Frames { frames, fps }) => {
+                // Half a frame early, so rounding can't skip the first one. Every
+                // frame is a keyframe, so ffmpeg lands on it without decoding others.
+                let start = (frames.start as f64 - 0.5).max(0.0) / fps;
+                command.args(["-ss", &format!("{:.6}", start)]);
+            }
+            Some(Seek::Tail(seconds)) => {
+                command.args(["-sseof", &format!("-{:.6}", seconds)]);
+            }
+            None => {}
+        }
+        command.args([
+            "-i", &self.input_path,
+            "-f", "rawvideo",
+            "-pix_fmt", PIXEL_FORMAT,
+        ]);
+        if let Some(Seek::Frames { frames, .. }) = &seek {
+            command.args(["-frames:v", &(frames.end - frames.start).to_string()]);
+        }
+        let mut child = command
+            .arg("-")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null()) // Suppress logs
+            .spawn()
+            .context("Failed to spawn ffmpeg decodder")?;
+        let stdout = child.stdout.take().context("Failed to open ffmpeg stdout")?;
+        Ok((child, stdout))
+    }
+
+    /// Reads frames until one is a readable header frame. Returns the header,
+    /// the levels measured on its calibration patches and its frame index.
+    fn find_header(
+        &self,
+        frames: &mut impl Read,
+        frame_buffer: &mut [u8],
+        stream: Geometry,
+    ) -> Result<(FileHeader, Calibration, u64)> {
+        for frame_index in 0.. {
+            if frames.read_exact(frame_buffer).is_err() {
+                return Err(anyhow::anyhow!("No readable header frame found. Is this a compatible video?"));
+            }
+            let calibration = self.measure_calibration(frame_buffer, stream);
+            let nominal = calibration.clone().unwrap_or_else(Calibration::nominal);
+            let Ok(bytes) = self.decode_bootstrap(frame_buffer, stream, &nominal) else {
+                continue;
+            };
+            let header = match FileHeader::from_bytes(&bytes) {
+                Ok(header) => header,
+                // A header this build can't read, e.g. from a newer b2v: every copy will say the same
+                Err(e) if FileHeader::is_binary(&bytes) => return Err(e),
+                Err(_) => continue,
+            };
+            if calibration.is_none() {
+                eprintln!("WARNING: Calibration patches unreadable, assuming nominal levels");
+            }
+            return Ok((header, nominal, frame_index));
+        }
+        unreachable!()
+    }
+
+    /// Reads the shard in the body of a data frame and checks it against its
+    /// `frame_header`.
+    fn read_shard(
+        &self,
+        frame: &ScaledFrame,
+        frame_header: FrameHeader,
+        calibration: &Calibration,
+        params: &EncodingParams,
+    ) -> Result<ShardRead> {
+        Ok(self.read_body(frame, frame_header, calibration, params, false)?.0)
+    }
+
+    /// `read_shard`, also returning the band the shard was read from. With
+    /// `keep_samples`, the band keeps what `scrub` measures margins by.
+    fn read_body(
+        &self,
+        frame: &ScaledFrame,
+        frame_header: FrameHeader,
+        calibration: &Calibration,
+        params: &EncodingParams,
+        keep_samples: bool,
+    ) -> Result<(ShardRead, DecodedBand)> {
+        let shard_size = params.shard_size();
+        let body = body_layout(frame.encoded, params.block_size);
+        let levels = self.frame_levels(frame, frame_header, calibration, params.modulation);
+        let mut band = self.read_band(frame, &body, params.modulation, &levels, keep_samples)?;
+        if band.bytes.len() < shard_size || !frame_header.verify(&band.bytes[..shard_size]) {
+            return Ok((ShardRead::Damaged, band));
+        }
+        // A matching CRC can still be a lucky guess when the picture
+        // is this degraded, so let RS rebuild the shard instead.
+        if band.weakest(0..shard_size) < self.options.min_confidence {
+            return Ok((ShardRead::Weak, band));
+        }
+        let mut payload = std::mem::take(&mut band.bytes);
+        payload.truncate(shard_size);
+        Ok((ShardRead::Verified(payload), band))
+    }
+
+    /// The calibrated levels, pinned to the black and white of this frame's
+    /// strip, since brightness can change from one frame to the next.
+    fn frame_levels(&self, frame: &ScaledFrame, frame_header: FrameHeader, calibration: &Calibration, modulation: Modulation) -> Vec<Levels> {
+        let mut levels = calibration.channel_levels(modulation);
+        if !self.options.fixed_levels {
+            if let Some(points) = self.measure_strip(frame, frame_header) {
+                for (levels, (black, white)) in levels.iter_mut().zip(points) {
+                    *levels = levels.stretch(black, white);
+                }
+            }
+        }
+        levels
+    }
+
+    /// Measures the calibration patches of a bootstrap frame, or `None` if
+    /// they are unreadable.
+    fn measure_calibration(&self, frame_buffer: &[u8], stream: Geometry) -> Option<Calibration> {
+        let grid = bootstrap_geometry(stream);
+        let frame = ScaledFrame::new(frame_buffer, stream, grid);
+        let mut observed = [[0.0; CALIBRATION_PATCHES]; 3];
+        let mut pixels = Vec::new();
+        for patch in 0..CALIBRATION_PATCHES {
+            // Median of the middle of the patch, clear of its edges
+            let columns = patch_columns(grid.width, patch);
+            let margin = columns.len() as f64 / 4.0;
+            let xs = columns.start as f64 + margin..columns.end as f64 - margin;
+            let ys = CALIBRATION_HEIGHT as f64 / 4.0..CALIBRATION_HEIGHT as f64 * 3.0 / 4.0;
+            pixels.clear();
+            frame.sample_region(xs, ys, &mut pixels);
+            for (c, curve) in observed.iter_mut().enumerate() {
+                let mut values: Vec<u8> = pixels.iter().map(|p| p[c]).collect();
+                values.sort_unstable();
+                curve[patch] = values[values.len() / 2] as f64;
+            }
+        }
+        Calibration::from_patches(observed)
+    }
+
+    /// Reads a header/trailer frame on the bootstrap grid, below the
+    /// calibration band, and recovers its payload from the protected copies.
+    fn decode_bootstrap(&self, frame_buffer: &[u8], stream: Geometry, calibration: &Calibration) -> Result<Vec<u8>> {
+        let grid = bootstrap_geometry(stream);
+        let frame = ScaledFrame::new(frame_buffer, stream, grid);
+        let levels = calibration.channel_levels(Modulation::BINARY);
+        let frame_data = self.decode_frame_to_bytes(&frame, &bootstrap_layout(grid), Modulation::BINARY, &levels)?;
+        bootstrap::recover(&frame_data)
+    }
+
+    /// Reads the FrameHeader strip at the top of a data/parity frame.
+    fn decode_frame_header(&self, frame: &ScaledFrame, calibration: &Calibration) -> Option<FrameHeader> {
+        let strip = strip_layout(frame.encoded.width);
+        let levels = calibration.channel_levels(Modulation::BINARY);
+        let strip_bytes = self
+            .decode_frame_to_bytes(frame, &strip, Modulation::BINARY, &levels)
+            .ok()?;
+        FrameHeader::from_strip(&strip_bytes)
+    }
+
+    /// Black and white of this frame per channel, from the strip blocks the
+    /// decoded `frame_header` says must be one or the other. `None` if the
+    /// strip has no blocks of either.
+    fn measure_strip(&self, frame: &ScaledFrame, frame_header: FrameHeader) -> Option<[(f64, f64); 3]> {
+        let strip_data = frame_header.to_bytes().ok()?.repeat(FRAME_HEADER_COPIES);
+        let strip = strip_layout(frame.encoded.width);
+
+        // Sampled block values per channel, split by the bit they carry
+        let mut samples = [[Vec::new(), Vec::new()], [Vec::new(), Vec::new()], [Vec::new(), Vec::new()]];
+        let mut pixels = Vec::new();
+        for block_idx in 0..strip.cells() {
+            pixels.clear();
+            frame.sample_cell(strip.cell(block_idx), Sampling::Mean, &mut pixels);
+            let bit = read_symbol(&strip_data, block_idx, 1) as usize;
+            for (c, channel) in samples.iter_mut().enumerate() {
+                let mean = pixels.iter().map(|p| p[c] as f64).sum::<f64>() / pixels.len() as f64;
+                channel[bit].push(mean);
             }
-            row_bytes
+        }
+
+        let median = |values: &mut Vec<f64>| {
+            values.sort_unstable_by(f64::total_cmp);
+            values.get(values.len() / 2).copied()
+        };
+        let mut points = [(0.0, 0.0); 3];
+        for (point, [black, white]) in points.iter_mut().zip(samples.iter_mut()) {
+            *point = (median(black)?, median(white)?);
+        }
+        Some(points)
+    }
+
+    /// Reads `modulation.bits_per_block()` bits per cell of `layout` (in
+    /// encoded coordinates) from `frame`: the data area of a bootstrap frame,
+    /// or the strip or body of a data frame.
+    fn decode_frame_to_bytes(
+        &self,
+        frame: &ScaledFrame,
+        layout: &Layout,
+        modulation: Modulation,
+        levels: &[Levels],
+    ) -> Result<Vec<u8>> {
+        Ok(self.read_band(frame, layout, modulation, levels, false)?.bytes)
+    }
+
+    /// `decode_frame_to_bytes`, keeping the confidence of every symbol read.
+    /// With `keep_samples`, also every symbol, the value it was read from and
+    /// the levels it was read against.
+    fn read_band(
+        &self,
+        frame: &ScaledFrame,
+        layout: &Layout,
+        modulation: Modulation,
+        levels: &[Levels],
+        keep_samples: bool,
+    ) -> Result<DecodedBand> {
+        let cols = layout.cols();
+        let max_bytes = layout.capacity(modulation.bits_per_block());
+        let sampling = self.options.sampling;
+        let levels = self.band_levels(frame, layout, levels);
+
+        // Parallel symbol extraction, one row of cells per task, in the
+        // canonical layout order the encoder draws them in.
+        let row_results: Vec<Vec<(u8, u8, f32)>> = (0..layout.cell_rows()).into_par_iter().map(|row_idx| {
+            let mut pixels = Vec::new();
+            let mut values = Vec::new();
+            let mut symbols = Vec::with_capacity(cols * modulation.channels());
+            for block_idx in row_idx * cols..(row_idx + 1) * cols {
+                pixels.clear();
+                frame.sample_cell(layout.cell(block_idx), sampling, &mut pixels);
+
+                // One symbol per channel. Gray mode only reads the Red channel [0].
+                for (c, levels) in levels.iter().enumerate() {
+                    values.clear();
+                    values.extend(pixels.iter().map(|p| p[c]));
+                    let (symbol, confidence) = sampling.read(&mut values, levels);
+                    let value = if keep_samples { sampling.value(&mut values) as f32 } else { 0.0 };
+                    symbols.push((symbol, confidence, value));
+                }
+            }
+            symbols
         }).collect();
-        
-        // Flatten
-        let mut flat_bytes = Vec::with_capacity(max_bytes);
-        for rb in row_results {
-            flat_bytes.extend(rb);
-        }
-        
-        Ok(flat_bytes)
+
+        let mut symbols = Vec::new();
+        let mut confidence = Vec::new();
+        let mut values = Vec::new();
+        for (symbol, c, value) in row_results.into_iter().flatten() {
+            symbols.push(symbol);
+            confidence.push(c);
+            values.push(value);
+        }
+        let bytes = pack_symbols(symbols.iter().copied(), modulation.bits_per_channel, max_bytes);
+        Ok(DecodedBand {
+            bytes,
+            confidence,
+            bits_per_symbol: modulation.bits_per_channel as usize,
+            samples: keep_samples.then_some(BandSamples { symbols, values, levels }),
+        })
+    }
+
+    /// Adapts the levels to this band's own histogram of block centres, to
+    /// follow whatever brightness drift is left.
+    fn band_levels(&self, frame: &ScaledFrame, layout: &Layout, levels: &[Levels]) -> Vec<Levels> {
+        if self.options.fixed_levels {
+            return levels.to_vec();
+        }
+        let histograms = (0..layout.cells())
+            .into_par_iter()
+            .fold(
+                || vec![[0u32; 256]; levels.len()],
+                |mut histograms, block_idx| {
+                    let pixel = frame.sample_centre(layout.cell(block_idx));
+                    for (c, histogram) in histograms.iter_mut().enumerate() {
+                        histogram[pixel[c] as usize] += 1;
+                    }
+                    histograms
+                },
+            )
+            .reduce(
+                || vec![[0u32; 256]; levels.len()],
+                |mut a, b| {
+                    for (a, b) in a.iter_mut().zip(&b) {
+                        a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                    }
+                    a
+                },
+            );
+        levels.iter().zip(&histograms).map(|(l, h)| l.refine(h)).collect()
+    }
+}
+
+/// Which part of the video to read.
+enum Seek {
+    /// Frames by index in the video, and the frame rate to find them by
+    Frames { frames: Range<u64>, fps: f64 },
+    /// Everything from this many seconds before the end
+    Tail(f64),
+}
+
+/// The shard a data frame turned out to hold.
+enum ShardRead {
+    Verified(Vec<u8>),
+    /// Matches its CRC, but some bit was read with less than the minimum confidence
+    Weak,
+    Damaged,
+}
+
+/// Bytes read from a band of blocks, along with how confidently each of
+/// their symbols was read (see `Levels::classify`).
+struct DecodedBand {
+    bytes: Vec<u8>,
+    /// One entry per symbol, in bit stream order
+    confidence: Vec<u8>,
+    bits_per_symbol: usize,
+    /// Kept on request, for `scrub`
+    samples: Option<BandSamples>,
+}
+
+/// Every symbol of a band as read, the value it was read from and the levels
+/// it was read against, after adapting them to the band.
+struct BandSamples {
+    symbols: Vec<u8>,
+    values: Vec<f32>,
+    levels: Vec<Levels>,
+}
+
+impl DecodedBand {
+    /// Confidence of bit `bit` of `bytes`, that of the symbol carrying it.
+    fn bit_confidence(&self, bit: usize) -> u8 {
+        self.confidence.get(bit / self.bits_per_symbol).copied().unwrap_or(0)
+    }
+
+    /// Lowest bit confidence over `bytes[range]`.
+    fn weakest(&self, range: Range<usize>) -> u8 {
+        let symbols = range.start * 8 / self.bits_per_symbol..(range.end * 8).div_ceil(self.bits_per_symbol);
+        symbols
+            .map(|symbol| self.bit_confidence(symbol * self.bits_per_symbol))
+            .min()
+            .unwrap_or(255)
+    }
+}
+
+/// A raw frame from ffmpeg along with the geometry it was encoded at, so block
+/// positions can be mapped onto a copy a host has rescaled.
+struct ScaledFrame<'a> {
+    pixels: &'a [u8],
+    stream: Geometry,
+    encoded: Geometry,
+    scale_x: f64,
+    scale_y: f64,
+}
+
+impl<'a> ScaledFrame<'a> {
+    fn new(pixels: &'a [u8], stream: Geometry, encoded: Geometry) -> Self {
+        Self {
+            pixels,
+            stream,
+            encoded,
+            scale_x: stream.width as f64 / encoded.width as f64,
+            scale_y: stream.height as f64 / encoded.height as f64,
+        }
+    }
+
+    /// RGB pixel at a position given in encoded pixel coordinates.
+    fn sample(&self, x: f64, y: f64) -> [u8; 3] {
+        let sx = ((x * self.scale_x) as usize).min(self.stream.width - 1);
+        let sy = ((y * self.scale_y) as usize).min(self.stream.height - 1);
+        let idx = (sy * self.stream.width + sx) * 3;
+        [self.pixels[idx], self.pixels[idx + 1], self.pixels[idx + 2]]
+    }
+
+    /// Pixel at the centre of a cell, given as its columns and rows.
+    fn sample_centre(&self, (xs, ys): (Range<usize>, Range<usize>)) -> [u8; 3] {
+        self.sample((xs.start + xs.end) as f64 / 2.0, (ys.start + ys.end) as f64 / 2.0)
+    }
+
+    /// Appends the pixels `sampling` reads of a cell to `out`: its centre
+    /// pixel, or its interior.
+    fn sample_cell(&self, (xs, ys): (Range<usize>, Range<usize>), sampling: Sampling, out: &mut Vec<[u8; 3]>) {
+        if sampling == Sampling::Centre {
+            out.push(self.sample_centre((xs, ys)));
+            return;
+        }
+        let (inset_x, inset_y) = (sampling.inset(xs.len()) as f64, sampling.inset(ys.len()) as f64);
+        self.sample_region(
+            xs.start as f64 + inset_x..xs.end as f64 - inset_x,
+            ys.start as f64 + inset_y..ys.end as f64 - inset_y,
+            out,
+        );
+    }
+
+    /// Appends every stream pixel covering the area `xs` x `ys` (in encoded
+    /// pixel coordinates) to `out`, at least one even if the stream was
+    /// scaled down so far that the area falls between pixels.
+    fn sample_region(&self, xs: Range<f64>, ys: Range<f64>, out: &mut Vec<[u8; 3]>) {
+        let to_stream = |range: Range<f64>, scale: f64, size: usize| {
+            let start = ((range.start * scale).round() as usize).min(size - 1);
+            let end = ((range.end * scale).round() as usize).clamp(start + 1, size);
+            start..end
+        };
+        let sxs = to_stream(xs, self.scale_x, self.stream.width);
+        for sy in to_stream(ys, self.scale_y, self.stream.height) {
+            let row = sy * self.stream.width;
+            out.extend(sxs.clone().map(|sx| {
+                let idx = (row + sx) * 3;
+                [self.pixels[idx], self.pixels[idx + 1], self.pixels[idx + 2]]
+            }));
+        }
+    }
+}
+
+/// A chunk as handed back by the `ChunkAssembler`. `data` holds the
+/// concatenated data shards, or `None` if too many shards were lost.
+struct DecodedChunk {
+    index: u64,
+    data: Option<Vec<u8>>,
+    erased: usize,
+}
+
+/// The decoded file on its way out, hashed and counted as it is written.
+struct Output {
+    sink: Sink,
+    hasher: Sha256,
+    written: u64,
+    progress: ProgressBar,
+}
+
+enum Sink {
+    File(std::fs::File),
+    Archive(Box<ArchiveWriter>),
+    Stream(SyncSender<Vec<u8>>),
+    /// Only verifying
+    Discard,
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = match &mut self.sink {
+            Sink::File(file) => file.write(buf)?,
+            Sink::Archive(archive) => archive.write(buf)?,
+            Sink::Stream(sender) => {
+                sender
+                    .send(buf.to_vec())
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Stream closed"))?;
+                buf.len()
+            }
+            Sink::Discard => buf.len(),
+        };
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        self.progress.inc(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.sink {
+            Sink::File(file) => file.flush(),
+            Sink::Archive(archive) => archive.flush(),
+            Sink::Stream(_) | Sink::Discard => Ok(()),
+        }
+    }
+}
+
+/// Collects the shards of one chunk at a time, placed by the shard index from
+/// their frame header rather than by arrival order.
+struct ChunkAssembler {
+    rs: ReedSolomon,
+    data_shards: usize,
+    chunk_index: u64,
+    shards: Vec<Option<Vec<u8>>>,
+}
+
+impl ChunkAssembler {
+    fn new(data_shards: usize, parity_shards: usize) -> Result<Self> {
+        Ok(Self {
+            rs: ReedSolomon::new(data_shards, parity_shards)?,
+            data_shards,
+            chunk_index: 0,
+            shards: vec![None; data_shards + parity_shards],
+        })
+    }
+
+    /// Stores a verified shard. Duplicates of a shard we already hold are ignored.
+    fn insert(&mut self, shard_index: usize, payload: Vec<u8>) {
+        if let Some(slot) = self.shards.get_mut(shard_index) {
+            if slot.is_none() {
+                *slot = Some(payload);
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.shards.iter().all(|s| s.is_some())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.is_none())
+    }
+
+    /// Reconstructs the current chunk from whatever shards arrived and moves on to the next one.
+    fn finish(&mut self) -> DecodedChunk {
+        // Shards that are still None are erasures for RS
+        let erased = self.shards.iter().filter(|s| s.is_none()).count();
+        let data = match self.rs.reconstruct_data(&mut self.shards) {
+            Ok(_) => Some(
+                self.shards
+                    .iter()
+                    .take(self.data_shards)
+                    .flat_map(|s| s.as_deref().unwrap_or_default())
+                    .copied()
+                    .collect(),
+            ),
+            Err(_) => None,
+        };
+
+        let chunk = DecodedChunk {
+            index: self.chunk_index,
+            data,
+            erased,
+        };
+        self.chunk_index += 1;
+        self.shards.fill(None);
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{EncodeOptions, Encoder};
+    use crate::layout::BlockSize;
+    use crate::utils::{EncodingParams, DEFAULT_FPS};
+    use crate::vectors;
+
+    fn params(geometry: Geometry, block_size: BlockSize, modulation: Modulation) -> EncodingParams {
+        EncodingParams {
+            geometry,
+            fps: DEFAULT_FPS,
+            block_size,
+            modulation,
+            data_shards: 10,
+            parity_shards: 2,
+        }
+    }
+
+    /// Renders a full shard of noise into a data frame and reads it back.
+    fn round_trip(params: EncodingParams) {
+        let mut state = 0x2545_F491u32;
+        let data: Vec<u8> = (0..params.shard_size())
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let frame_header = FrameHeader::new(1, 0, 0, &data);
+        let encoder = Encoder::new(Vec::new(), String::new(), params, EncodeOptions::default());
+        let mut pixels = Vec::new();
+        encoder.write_frame(&mut pixels, Some(frame_header), &data).unwrap();
+
+        let decoder = Decoder::new(String::new(), Destination::Verify, DecodeOptions::default());
+        let frame = ScaledFrame::new(&pixels, params.geometry, params.geometry);
+        let calibration = Calibration::nominal();
+        assert_eq!(decoder.decode_frame_header(&frame, &calibration), Some(frame_header));
+
+        let body = body_layout(params.geometry, params.block_size);
+        let levels = calibration.channel_levels(params.modulation);
+        let decoded = decoder.decode_frame_to_bytes(&frame, &body, params.modulation, &levels).unwrap();
+        assert!(
+            decoded == data,
+            "{} blocks at {} with {:?}",
+            params.block_size,
+            params.geometry,
+            params.modulation
+        );
+    }
+
+    #[test]
+    fn test_frame_round_trip_across_block_sizes() {
+        for size in 1..=16 {
+            round_trip(params(Geometry::new(960, 540), BlockSize::square(size), Modulation::BINARY));
+        }
+        for size in [7, 9, 11] {
+            round_trip(params(Geometry::new(1080, 1920), BlockSize::square(size), Modulation::BINARY));
+        }
+        let colour = Modulation::new(2, true).unwrap();
+        for (width, height) in [(8, 4), (3, 5), (11, 7), (1, 2)] {
+            round_trip(params(Geometry::new(640, 360), BlockSize { width, height }, colour));
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_round_trip() {
+        let params = params(Geometry::new(1280, 720), BlockSize::square(4), Modulation::BINARY);
+        let header = FileHeader::new("a.bin".to_string(), 42, "PENDING".to_string(), &params);
+        let encoder = Encoder::new(Vec::new(), String::new(), params, EncodeOptions::default());
+        let mut pixels = Vec::new();
+        encoder.write_frame(&mut pixels, None, &header.to_bytes().unwrap()).unwrap();
+
+        let decoder = Decoder::new(String::new(), Destination::Verify, DecodeOptions::default());
+        let calibration = decoder.measure_calibration(&pixels, params.geometry).unwrap();
+        assert_eq!(calibration, Calibration::nominal());
+        let bytes = decoder.decode_bootstrap(&pixels, params.geometry, &calibration).unwrap();
+        assert_eq!(FileHeader::from_bytes(&bytes).unwrap().params().unwrap(), params);
+    }
+
+    #[test]
+    fn test_find_header_skips_damaged_start() {
+        let params = params(Geometry::new(640, 360), BlockSize::square(4), Modulation::BINARY);
+        let header = FileHeader::new("a.bin".to_string(), 42, "PENDING".to_string(), &params);
+        let encoder = Encoder::new(Vec::new(), String::new(), params, EncodeOptions::default());
+
+        // A wiped frame 0, a data frame, then a header copy
+        let mut video = vec![0u8; params.geometry.frame_size()];
+        let shard = vec![0x5Au8; params.shard_size()];
+        encoder.write_frame(&mut video, Some(FrameHeader::new(1, 0, 0, &shard)), &shard).unwrap();
+        encoder.write_frame(&mut video, None, &header.to_bytes().unwrap()).unwrap();
+
+        let decoder = Decoder::new(String::new(), Destination::Verify, DecodeOptions::default());
+        let mut frame_buffer = vec![0u8; params.geometry.frame_size()];
+        let (found, _, frame_index) = decoder
+            .find_header(&mut video.as_slice(), &mut frame_buffer, params.geometry)
+            .unwrap();
+        assert_eq!(frame_index, 2);
+        assert_eq!(found.params().unwrap(), params);
+
+        let mut truncated = &video[..2 * params.geometry.frame_size()];
+        assert!(decoder.find_header(&mut truncated, &mut frame_buffer, params.geometry).is_err());
+    }
+
+    #[test]
+    fn test_decodes_conformance_vectors() {
+        let decoder = Decoder::new(String::new(), Destination::Verify, DecodeOptions::default());
+        let calibration = Calibration::nominal();
+        for vector in vectors::all() {
+            let pixels = vector.frame();
+            let geometry = vector.params.geometry;
+            let decoded = match vector.frame_header {
+                Some(frame_header) => {
+                    let frame = ScaledFrame::new(&pixels, geometry, geometry);
+                    assert_eq!(decoder.decode_frame_header(&frame, &calibration), Some(frame_header), "{}", vector.name);
+                    let body = body_layout(geometry, vector.params.block_size);
+                    let levels = calibration.channel_levels(vector.params.modulation);
+                    decoder.decode_frame_to_bytes(&frame, &body, vector.params.modulation, &levels).unwrap()
+                }
+                None => {
+                    assert_eq!(decoder.measure_calibration(&pixels, geometry), Some(Calibration::nominal()));
+                    decoder.decode_bootstrap(&pixels, geometry, &calibration).unwrap()
+                }
+            };
+            assert!(decoded == vector.data, "Frame {} decoded to the wrong bytes", vector.name);
+        }
+    }
+
+    #[test]
+    fn test_report_parity_margin() {
+        let chunk = |index, status, erased| ChunkReport { index, status, erased };
+        let mut report = DecodeReport {
+            signature: SignatureStatus::Unsigned,
+            hash: HashCheck::Verified,
+            parity_shards: 4,
+            chunks: vec![chunk(0, ChunkStatus::Clean, 0), chunk(1, ChunkStatus::Repaired, 3)],
+        };
+        assert_eq!(report.min_margin(), Some(1));
+        assert!(report.check_hash().is_ok());
+
+        // Lost chunks have no margin to speak of
+        report.chunks.push(chunk(2, ChunkStatus::Unrecoverable, 5));
+        assert_eq!(report.min_margin(), Some(1));
+        assert_eq!(report.count(ChunkStatus::Unrecoverable), 1);
+        report.hash = HashCheck::Mismatch { expected: "00".to_string(), calculated: "11".to_string() };
+        assert!(report.check_hash().is_err());
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["hash"]["status"], "mismatch");
+        assert_eq!(json["signature"]["status"], "unsigned");
+        assert_eq!(json["chunks"][1]["status"], "repaired");
     }
 }
@@ -1,52 +1,104 @@
-use crate::utils::{FileHeader, FRAME_RATE, PIXEL_FORMAT, VIDEO_HEIGHT, VIDEO_WIDTH};
+use crate::utils::{
+    body_layout, bootstrap_geometry, bootstrap_layout, strip_layout, EncodingParams, FileHeader, FileTrailer,
+    FrameHeader, Geometry, DEFAULT_HEADER_INTERVAL, EXT_ARCHIVE, EXT_COMPRESSION, EXT_ENCRYPTION, EXT_HEADER_INTERVAL, EXT_INDEX, EXT_METADATA, EXT_RECIPIENTS,
+    FRAME_HEADER_COPIES, PIXEL_FORMAT,
+};
+use crate::archive::{Archive, Manifest, ARCHIVE_FORMAT};
+use crate::bootstrap;
+use crate::compression::{ChunkCompressor, Compression, SAMPLE_SIZE};
+use crate::crypto::{Encryption, KdfParams, Recipient, Recipients};
+use crate::index::{Index, StreamLayout};
+use crate::metadata::{file_name, Metadata};
+use crate::signing::{MerkleTree, SignKey};
+use crate::layout::Layout;
+use crate::calibration::{patch_columns, patch_value, CALIBRATION_HEIGHT, CALIBRATION_PATCHES};
+use crate::modulation::{read_symbol, Modulation};
 use anyhow::{Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use reed_solomon_erasure::galois_8::ReedSolomon;
 use sha2::{Digest, Sha256};
+use std::cell::RefCell;
 use std::io::{Read, Write};
 use std::process::{Command, Stdio};
 
+/// Encoder settings that don't affect how the data is laid out, so they
+/// aren't needed to decode and stay out of `EncodingParams`.
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    /// FFmpeg codec
+    pub codec: String,
+    /// Chunks between copies of the header frame; 0 leaves only the first and last
+    pub header_interval: u64,
+    /// Encrypt the chunks with a key derived from this passphrase
+    pub passphrase: Option<String>,
+    /// Encrypt the chunks to these public keys instead
+    pub recipients: Vec<Recipient>,
+    /// Sign the header, hash and chunks with this key, in the trailer
+    pub sign_key: Option<SignKey>,
+    /// Compress the input with zstd at this level, unless it doesn't compress
+    pub compression_level: Option<i32>,
+    /// Record extended attributes along with permissions and times
+    pub xattrs: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            codec: "ffv1".to_string(),
+            header_interval: DEFAULT_HEADER_INTERVAL,
+            passphrase: None,
+            recipients: Vec::new(),
+            sign_key: None,
+            compression_level: None,
+            xattrs: false,
+        }
+    }
+}
+
 pub struct Encoder {
-    input_path: String,
+    /// One file, or several files and directories to archive together
+    inputs: Vec<String>,
     output_path: String,
-    block_size: usize,
-    data_shards: usize,
-    parity_shards: usize,
-    codec: String,
+    params: EncodingParams,
+    options: EncodeOptions,
 }
 
 impl Encoder {
     pub fn new(
-        input_path: String,
+        inputs: Vec<String>,
         output_path: String,
-        block_size: usize,
-        data_shards: usize,
-        parity_shards: usize,
-        codec: String,
+        params: EncodingParams,
+        options: EncodeOptions,
     ) -> Self {
         Self {
-            input_path,
+            inputs,
             output_path,
-            block_size,
-            data_shards,
-            parity_shards,
-            codec,
+            params,
+            options,
         }
     }
 
     pub fn run(&self) -> Result<()> {
-        let mut file = std::fs::File::open(&self.input_path).context("Failed to open input file")?;
-        let file_size = file.metadata()?.len();
+        self.encode(Input::new(&self.inputs, self.options.xattrs)?)?;
+        Ok(())
+    }
+
+    /// Encodes data decoded from another video instead of the inputs.
+    /// Returns the hash of what it read.
+    pub fn run_stream(&self, stream: StreamInput) -> Result<String> {
+        self.encode(Input::Stream(stream))
+    }
+
+    fn encode(&self, input: Input) -> Result<String> {
+        self.params.validate()?;
+        let file_size = input.size()?;
         
-        // Calculate frame capacity
-        let cols = VIDEO_WIDTH / self.block_size;
-        let rows = VIDEO_HEIGHT / self.block_size;
-        let bits_per_frame = cols * rows;
-        let bytes_per_frame = bits_per_frame / 8;
-
-        println!("Video Resolution: {}x{}", VIDEO_WIDTH, VIDEO_HEIGHT);
-        println!("Block Size: {}x{}", self.block_size, self.block_size);
+        // Calculate frame capacity (below the frame header strip)
+        let bytes_per_frame = self.params.shard_size();
+
+        println!("Video Resolution: {} @ {} fps", self.params.geometry, self.params.fps);
+        println!("Block Size: {}", self.params.block_size);
         println!("Frame Capacity: {} bytes", bytes_per_frame);
 
         // Setup FFmpeg
@@ -54,10 +106,10 @@ impl Encoder {
             .args([
                 "-f", "rawvideo",
                 "-pixel_format", PIXEL_FORMAT,
-                "-video_size", &format!("{}x{}", VIDEO_WIDTH, VIDEO_HEIGHT),
-                "-framerate", &format!("{}", FRAME_RATE),
+                "-video_size", &self.params.geometry.to_string(),
+                "-framerate", &self.params.fps.to_string(),
                 "-i", "pipe:0",
-                "-c:v", &self.codec,
+                "-c:v", &self.options.codec,
                 "-g", "1", // Keyframe every frame for robustness
                 "-y",
                 &self.output_path,
@@ -71,7 +123,7 @@ impl Encoder {
         let mut ffmpeg_stdin = child.stdin.take().context("Failed to open ffmpeg stdin")?;
 
         // Initialize RS
-        let rs = ReedSolomon::new(self.data_shards, self.parity_shards)
+        let rs = ReedSolomon::new(self.params.data_shards, self.params.parity_shards)
             .context("Failed to create ReedSolomon")?;
 
         // Calculate hashing and progress
@@ -82,31 +134,113 @@ impl Encoder {
             .progress_chars("#>-"));
 
         // Header
-        let header = FileHeader::new(
-            self.input_path.clone(),
+        // Just the name, where the input was found is nobody's business
+        let name = match &input {
+            Input::Stream(stream) => stream.name.clone(),
+            _ => file_name(&self.inputs[0])?,
+        };
+        let mut header = FileHeader::new(
+            name,
             file_size,
-            self.block_size as u32,
-            "PENDING".to_string(), // Hash will be verified at end of decode, but for header we might need to pre-calc if we want it perfect, 
-                                   // OR we put the hash at the end? 
-                                   // The user requirement says "Header ... Hash SHA256". 
-                                   // If we stream, we can't know hash at start. 
-                                   // We will compute hash as we read. But we write header first.
-                                   // Compromise: Write placeholder in header, or read file twice.
-                                   // For 100GB file, reading twice is bad. 
-                                   // Let's write the hash of the *original* file if possible? 
-                                   // Or maybe we can't.
-                                   // User says "Integirta Dati... File deve essere recuperabile".
-                                   // Decoder checks hash.
-                                   // Let's just put a placeholder or "0" for now and print the real hash at the end for the user to store?
-                                   // Or effectively we accept that the header hash is only useful if we pre-calculate.
-                                   // Let's pre-calculate for MVP if file is small? No "100GB+".
-                                   // OK, we will append a footer with the hash? 
-                                   // User required Header.
-                                   // Let's leave hash empty in header and log it at the end.
-            self.data_shards,
-            self.parity_shards,
+            // We stream the input, so the hash is only known once everything
+            // has been written. The real value goes into the trailer frame.
+            "PENDING".to_string(),
+            &self.params,
         );
-        
+
+        header.set_extension(EXT_HEADER_INTERVAL, self.options.header_interval.to_be_bytes().to_vec());
+
+        // The KDF settings and salt go into the header, the key stays with us
+        let cipher = match &self.options.passphrase {
+            Some(passphrase) => {
+                println!("Encryption: XChaCha20-Poly1305, key derived with Argon2id");
+                let (encryption, cipher) = Encryption::new(passphrase, KdfParams::default())?;
+                header.set_extension(EXT_ENCRYPTION, encryption.to_bytes()?);
+                Some(cipher)
+            }
+            None if !self.options.recipients.is_empty() => {
+                println!("Encryption: XChaCha20-Poly1305, to {} recipient(s)", self.options.recipients.len());
+                let (recipients, cipher) = Recipients::new(&self.options.recipients)?;
+                header.set_extension(EXT_RECIPIENTS, recipients.to_bytes());
+                Some(cipher)
+            }
+            None => None,
+        };
+
+        match &input {
+            Input::File(path) => {
+                let mut metadata = Metadata::read(std::path::Path::new(path), self.options.xattrs)?;
+                header.set_extension(EXT_METADATA, metadata.to_bytes()?);
+                // The header is 1 KiB all told, large attributes don't fit
+                if header.to_bytes().is_err() && !metadata.xattrs.is_empty() {
+                    eprintln!("WARNING: Extended attributes don't fit in the header, leaving them out");
+                    metadata.xattrs.clear();
+                    header.set_extension(EXT_METADATA, metadata.to_bytes()?);
+                }
+            }
+            Input::Archive(archive) => {
+                let files = archive.manifest.entries.iter().filter(|e| e.sha256.is_some()).count();
+                println!("Archive: {} files, {} entries", files, archive.manifest.entries.len());
+                header.set_extension(EXT_ARCHIVE, vec![ARCHIVE_FORMAT]);
+            }
+            Input::Stream(stream) => {
+                if let Some(metadata) = &stream.metadata {
+                    header.set_extension(EXT_METADATA, metadata.to_bytes()?);
+                }
+                if stream.archive {
+                    header.set_extension(EXT_ARCHIVE, vec![ARCHIVE_FORMAT]);
+                }
+            }
+        }
+
+        // Decided on a sample of the input, since the header has to record it up front
+        let compression = match self.options.compression_level {
+            Some(level) => {
+                let compression = Compression::new(level);
+                // A stream can only be read once, so it's compressed regardless
+                let worth_it = match &input {
+                    Input::Stream(_) => true,
+                    _ => {
+                        let mut sample = Vec::new();
+                        input.reader()?.take(SAMPLE_SIZE as u64).read_to_end(&mut sample)?;
+                        compression.worth_it(&sample)?
+                    }
+                };
+                if Compression::min_input(header.payload_size()?) == 0 {
+                    println!("Compression: skipped, chunks are too small to hold a compressed frame");
+                    None
+                } else if worth_it {
+                    println!("Compression: zstd level {}", level);
+                    header.set_extension(EXT_COMPRESSION, compression.to_bytes()?);
+                    Some(compression)
+                } else {
+                    println!("Compression: skipped, the input doesn't compress");
+                    None
+                }
+            }
+            None => None,
+        };
+
+        // Where each archived file lands, so one can be extracted without
+        // decoding the rest. A compressed stream can't be entered midway.
+        let index = match input.manifest() {
+            Some(manifest) if compression.is_none() => {
+                let mut layout = StreamLayout {
+                    payload_size: header.payload_size()? as u64,
+                    total_shards: self.params.total_shards() as u64,
+                    header_interval: self.options.header_interval,
+                    first_data_frame: 1,
+                };
+                // Entries are fixed-size, so the count doesn't depend on the frame numbers in them
+                let frames = Index::new(manifest, &layout)?.frame_count(cipher.is_some())?;
+                layout.first_data_frame += frames as u64;
+                println!("Index: {} frame(s)", frames);
+                header.set_extension(EXT_INDEX, (frames as u32).to_be_bytes().to_vec());
+                Some(Index::new(manifest, &layout)?)
+            }
+            _ => None,
+        };
+
         // Write Header Frame(s)
         // We write the header validation logic in utils or here?
         // Let's write raw header bytes repeatedly to fill a frame or just use the same encoding logic?
@@ -120,131 +254,312 @@ impl Encoder {
         // But we need to know settings to decode.
         // Chicken and egg.
         // Solution: Standardized "Bootstrap" Frame at Frame 0.
-        // Frame 0 always: 1920x1080, Block Size 4, containing the Header.
-        self.write_frame(&mut ffmpeg_stdin, &header_bytes, true, 4)?; // Force Block Size 4 for Header
+        // Frame 0 always: bootstrap grid (Block Size 4 at 1080p), containing the Header.
+        self.write_frame(&mut ffmpeg_stdin, None, &header_bytes)?;
+        if let Some(index) = &index {
+            for payload in index.to_frames(cipher.as_ref())? {
+                self.write_frame(&mut ffmpeg_stdin, None, &payload)?;
+            }
+        }
 
         // Process File
-        let shard_size = bytes_per_frame;
-        let chunk_size = shard_size * self.data_shards;
-        let mut buffer = vec![0u8; chunk_size];
+        let payload_size = header.payload_size()?;
+        let mut chunk = Vec::with_capacity(self.params.chunk_size());
+        let mut merkle_tree = MerkleTree::default();
+        let mut total_chunks = 0u64;
+        let mut frame_number = 1u32; // Frame 0 is the header
+
+        // The hash covers the input as it is, not as stored
+        let reader = HashingReader { inner: input.reader()?, hasher: &mut hasher, progress: &pb };
+        let mut source: Box<dyn Read> = match compression {
+            Some(compression) => Box::new(ChunkCompressor::new(reader, compression.level, payload_size)?),
+            None => Box::new(reader),
+        };
 
         loop {
-            let n = file.read(&mut buffer)?;
+            // Always a full chunk until the end of the input, the decoder relies on it
+            chunk.clear();
+            let n = (&mut source).take(payload_size as u64).read_to_end(&mut chunk)?;
             if n == 0 {
                 break;
             }
-            pb.inc(n as u64);
-            hasher.update(&buffer[..n]);
-
-            // Pad last chunk if needed
-            let mut data_shards: Vec<Vec<u8>> = buffer[..n]
-                .chunks(shard_size)
-                .map(|c| c.to_vec())
-                .collect();
-            
-            // Pad the last shard if it's partial
-            if let Some(last) = data_shards.last_mut() {
-                if last.len() < shard_size {
-                    last.resize(shard_size, 0);
-                }
+            // Copies of the header along the way, so losing the start of the
+            // video doesn't lose everything after it
+            let interval = self.options.header_interval;
+            if interval > 0 && total_chunks > 0 && total_chunks.is_multiple_of(interval) {
+                self.write_frame(&mut ffmpeg_stdin, None, &header_bytes)?;
             }
-            
-            // Pad with empty shards if we don't have enough data shards
-            while data_shards.len() < self.data_shards {
-                data_shards.push(vec![0u8; shard_size]);
+            if let Some(cipher) = &cipher {
+                cipher.seal(total_chunks, &mut chunk)?;
             }
 
-            // Calculate parity
-            // RS v6 expects a single vector containing both data and parity shards
-            for _ in 0..self.parity_shards {
-                data_shards.push(vec![0u8; shard_size]);
-            }
-            
-            rs.encode(&mut data_shards)?;
+            let data_shards = self.encode_chunk(&rs, &chunk)?;
+            merkle_tree.push(Some(&data_shards[..self.params.data_shards].concat()));
 
             // Write all shards (Data + Parity) as frames
-            for shard in data_shards.iter() {
-                self.write_frame(&mut ffmpeg_stdin, shard, false, self.block_size)?;
+            for (shard_index, shard) in data_shards.iter().enumerate() {
+                let frame_header = FrameHeader::new(frame_number, total_chunks as u32, shard_index as u16, shard);
+                self.write_frame(&mut ffmpeg_stdin, Some(frame_header), shard)?;
+                frame_number += 1;
             }
+            total_chunks += 1;
         }
 
 
+        drop(source);
         pb.finish_with_message("Encoding complete");
         
         // Finalize
         let result_hash = format!("{:x}", hasher.finalize());
         println!("Original File Hash: {}", result_hash);
-        println!("(Note: Header contains placeholder hash)");
+
+        // One last copy of the header, now with the real hash
+        let header = FileHeader { sha256_hash: result_hash.clone(), ..header };
+        self.write_frame(&mut ffmpeg_stdin, None, &header.to_bytes()?)?;
+
+        // Trailer Frame: same bootstrap encoding as the header, so the decoder
+        // can read it back without any knowledge of the data settings.
+        let mut trailer = FileTrailer::new(result_hash, total_chunks, file_size);
+        if let Some(sign_key) = &self.options.sign_key {
+            let merkle_root = merkle_tree.root().context("Merkle tree is missing a chunk")?;
+            sign_key.sign(&header, &mut trailer, merkle_root)?;
+            println!("Signed by: {}", sign_key.verify_key());
+        }
+        self.write_frame(&mut ffmpeg_stdin, None, &trailer.to_bytes()?)?;
 
         // Wait for ffmpeg
         // We drop stdin to close pipe
         drop(ffmpeg_stdin);
         child.wait()?;
 
-        Ok(())
+        Ok(header.sha256_hash)
     }
 
-    fn write_frame(&self, writer: &mut impl Write, data: &[u8], is_header: bool, block_size: usize) -> Result<()> {
-        // If header, we might just repeat the data to fill the frame?
-        // Or just write it once and pad.
-        // For robust header: Repeat the data until frame is full.
-        let bytes_per_frame = (VIDEO_WIDTH / block_size) * (VIDEO_HEIGHT / block_size) / 8;
-        
-        let mut frame_data = data.to_vec();
-        if is_header {
-             // Repeat header to fill frame for robustness
-             while frame_data.len() < bytes_per_frame {
-                 frame_data.extend_from_slice(data);
-             }
-             frame_data.truncate(bytes_per_frame);
+    /// Splits one chunk of input into `data_shards` shards of `shard_size`
+    /// bytes, zero-padding the last chunk of the file, and appends the parity
+    /// shards computed over them.
+    pub(crate) fn encode_chunk(&self, rs: &ReedSolomon, chunk: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let shard_size = self.params.shard_size();
+        let mut shards: Vec<Vec<u8>> = chunk.chunks(shard_size).map(|c| c.to_vec()).collect();
+
+        // Pad the last shard if it's partial
+        if let Some(last) = shards.last_mut() {
+            last.resize(shard_size, 0);
         }
 
-        // Parallel conversion of bytes -> pixels
-        let width = VIDEO_WIDTH;
-        let height = VIDEO_HEIGHT;
-        let cols = width / block_size;
-        
+        // Pad with empty shards if we don't have enough data shards, then
+        // leave room for parity: RS v6 expects data and parity in one vector
+        shards.resize(self.params.total_shards(), vec![0u8; shard_size]);
+        rs.encode(&mut shards)?;
+        Ok(shards)
+    }
+
+    /// Renders one frame at the configured geometry. Data/parity frames get
+    /// their FrameHeader strip and the shard below it; without a FrameHeader
+    /// this is a bootstrap frame (header/trailer) on the bootstrap grid.
+    pub(crate) fn write_frame(&self, writer: &mut impl Write, frame_header: Option<FrameHeader>, data: &[u8]) -> Result<()> {
+        let geometry = self.params.geometry;
+
         // Output buffer: RGB24
-        let mut pixel_buffer = vec![0u8; width * height * 3];
+        let mut pixel_buffer = vec![0u8; geometry.frame_size()];
+
+        match frame_header {
+            Some(frame_header) => {
+                // Strip at the top, repeated for majority voting
+                let strip_data = frame_header.to_bytes()?.repeat(FRAME_HEADER_COPIES);
+                let body = body_layout(geometry, self.params.block_size);
+                Self::draw_blocks(&mut pixel_buffer, geometry, geometry, &strip_layout(geometry.width), &strip_data, Modulation::BINARY);
+                Self::draw_blocks(&mut pixel_buffer, geometry, geometry, &body, data, self.params.modulation);
+            }
+            None => {
+                // Calibration patches on top, then the RS-protected header repeated to fill the frame
+                let grid = bootstrap_geometry(geometry);
+                let layout = bootstrap_layout(grid);
+                let protected = bootstrap::protect(data)?;
+                let frame_data: Vec<u8> = protected.iter().copied().cycle().take(layout.capacity(1)).collect();
+                Self::draw_calibration(&mut pixel_buffer, geometry, grid);
+                Self::draw_blocks(&mut pixel_buffer, geometry, grid, &layout, &frame_data, Modulation::BINARY);
+            }
+        }
+
+        writer.write_all(&pixel_buffer)?;
+        Ok(())
+    }
 
-        pixel_buffer.par_chunks_mut(width * 3 * block_size) // Process by rows (times block size)
+    /// Draws the calibration band of a bootstrap frame: gray patches from
+    /// black to white along the top `CALIBRATION_HEIGHT` rows of the grid.
+    fn draw_calibration(pixels: &mut [u8], frame: Geometry, grid: Geometry) {
+        pixels.chunks_mut(frame.width * 3)
+            .enumerate()
+            .take_while(|(y, _)| y * grid.height / frame.height < CALIBRATION_HEIGHT)
+            .for_each(|(_, row_pixels)| {
+                for patch in 0..CALIBRATION_PATCHES {
+                    let columns = patch_columns(grid.width, patch);
+                    let start = columns.start * frame.width / grid.width;
+                    let end = columns.end * frame.width / grid.width;
+                    row_pixels[start * 3..end * 3].fill(patch_value(patch));
+                }
+            });
+    }
+
+    /// Draws `frame_data` into the cells of `layout`, `modulation.bits_per_block()`
+    /// bits per cell, on a `frame`-sized RGB24 image. The layout is given in
+    /// `grid` coordinates and stretched onto the frame when the two differ
+    /// (bootstrap frames). Pixels outside the layout are left alone.
+    fn draw_blocks(
+        pixels: &mut [u8],
+        frame: Geometry,
+        grid: Geometry,
+        layout: &Layout,
+        frame_data: &[u8],
+        modulation: Modulation,
+    ) {
+        // Parallel conversion of bytes -> pixels
+        pixels.par_chunks_mut(frame.width * 3) // Process by pixel rows
             .enumerate()
-            .for_each(|(row_idx, row_pixels)| {
-                // row_pixels is a slice covering 'block_size' rows of pixels
-                let y_base = row_idx * block_size;
-                if y_base >= height { return; }
-
-                for bx in 0..cols {
-                    // Determine bit value
-                    let bit_idx = row_idx * cols + bx;
-                    let byte_idx = bit_idx / 8;
-                    let bit_offset = 7 - (bit_idx % 8);
-                    
-                    let color = if byte_idx < frame_data.len() {
-                        if (frame_data[byte_idx] >> bit_offset) & 1 == 1 {
-                            255u8
-                        } else {
-                            0u8
-                        }
-                    } else {
-                        0u8 // Padding
-                    };
-
-                    // Draw block
-                    for dy in 0..block_size {
-                        for dx in 0..block_size {
-                            let px_idx = (dy * width + (bx * block_size + dx)) * 3;
-                            if px_idx + 2 < row_pixels.len() {
-                                row_pixels[px_idx] = color;
-                                row_pixels[px_idx+1] = color;
-                                row_pixels[px_idx+2] = color;
-                            }
-                        }
+            .for_each(|(y, row_pixels)| {
+                // Which row of the grid this pixel row falls in
+                let grid_y = y * grid.height / frame.height;
+
+                for x in 0..frame.width {
+                    let Some(block_idx) = layout.cell_at(x * grid.width / frame.width, grid_y) else { continue };
+
+                    // Determine channel values (symbols past the data are padding).
+                    // Gray mode has one symbol per block, repeated on R, G and B.
+                    let channels = modulation.channels();
+                    let px_idx = x * 3;
+                    for c in 0..3 {
+                        let symbol = read_symbol(frame_data, block_idx * channels + c % channels, modulation.bits_per_channel);
+                        row_pixels[px_idx + c] = modulation.symbol_to_value(symbol);
                     }
                 }
             });
+    }
+}
 
-        writer.write_all(&pixel_buffer)?;
-        Ok(())
+/// What gets encoded: a single file as it is, or an archive stream of
+/// several files and directories.
+enum Input {
+    File(String),
+    Archive(Archive),
+    Stream(StreamInput),
+}
+
+impl Input {
+    fn new(inputs: &[String], xattrs: bool) -> Result<Self> {
+        match inputs {
+            [] => Err(anyhow::anyhow!("No input given")),
+            [path] if std::fs::metadata(path).with_context(|| format!("Failed to open input {}", path))?.is_file() => {
+                Ok(Self::File(path.clone()))
+            }
+            _ => Ok(Self::Archive(Archive::scan(inputs, xattrs)?)),
+        }
+    }
+
+    fn size(&self) -> Result<u64> {
+        match self {
+            Self::File(path) => Ok(std::fs::metadata(path)?.len()),
+            Self::Archive(archive) => archive.size(),
+            Self::Stream(stream) => Ok(stream.size),
+        }
+    }
+
+    fn manifest(&self) -> Option<&Manifest> {
+        match self {
+            Self::File(_) => None,
+            Self::Archive(archive) => Some(&archive.manifest),
+            Self::Stream(stream) => stream.manifest.as_ref(),
+        }
+    }
+
+    /// The input stream, from the start
+    fn reader(&self) -> Result<Box<dyn Read + '_>> {
+        match self {
+            Self::File(path) => Ok(Box::new(std::fs::File::open(path).context("Failed to open input file")?)),
+            Self::Archive(archive) => Ok(Box::new(archive.reader()?)),
+            Self::Stream(stream) => stream.reader.borrow_mut().take().context("A stream can only be read once"),
+        }
+    }
+}
+
+/// Data decoded from another video on its way into a new one, see `repair`,
+/// with what the other video's header said about it.
+pub struct StreamInput {
+    name: String,
+    size: u64,
+    metadata: Option<Metadata>,
+    archive: bool,
+    /// For an archive, its manifest, to index it by
+    manifest: Option<Manifest>,
+    reader: RefCell<Option<Box<dyn Read>>>,
+}
+
+impl StreamInput {
+    /// The stream `header` describes. An archive's manifest is read from the
+    /// start of `reader` right away, and put back in front.
+    pub fn new(header: &FileHeader, mut reader: Box<dyn Read>) -> Result<Self> {
+        let archive = header.is_archive()?;
+        let mut manifest = None;
+        if archive {
+            let (read, prefix) = Manifest::read(&mut reader, header.file_size)?;
+            // The index counts on the manifest taking up the same bytes it did
+            if read.to_bytes()? == prefix {
+                manifest = Some(read);
+            } else {
+                eprintln!("WARNING: Archive manifest doesn't serialize back the same, leaving out the index");
+            }
+            reader = Box::new(std::io::Cursor::new(prefix).chain(reader));
+        }
+        // Older videos stored the path the input was found at
+        let name = std::path::Path::new(&header.original_filename)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(&header.original_filename)
+            .to_string();
+        Ok(Self {
+            name,
+            size: header.file_size,
+            metadata: header.metadata()?,
+            archive,
+            manifest,
+            reader: RefCell::new(Some(reader)),
+        })
+    }
+}
+
+/// Hashes the input and reports progress as it is read, before it is compressed.
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Sha256,
+    progress: &'a ProgressBar,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.progress.inc(n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vectors;
+
+    #[test]
+    fn test_frames_match_conformance_vectors() {
+        let update = std::env::var_os(vectors::UPDATE_ENV).is_some();
+        for vector in vectors::all() {
+            let encoder = Encoder::new(Vec::new(), String::new(), vector.params, EncodeOptions::default());
+            let mut frame = Vec::new();
+            encoder.write_frame(&mut frame, vector.frame_header, &vector.data).unwrap();
+            if update {
+                std::fs::write(vector.path(), &frame).unwrap();
+                continue;
+            }
+            assert!(frame == vector.frame(), "Frame {} no longer matches its conformance vector", vector.name);
+        }
     }
 }
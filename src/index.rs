@@ -0,0 +1,248 @@
+use crate::archive::Manifest;
+use crate::crypto::{ChunkCipher, INDEX_NONCE, TAG_SIZE};
+use crate::utils::HEADER_SIZE;
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::Read;
+
+pub const INDEX_MAGIC: u64 = 0x4554_524E_4C49_4458; // "ETRNLIDX" in hex
+const PART_HEADER_SIZE: usize = 18; // magic (u64) + part (u32) + parts (u32) + length (u16)
+const PART_CAPACITY: usize = HEADER_SIZE - PART_HEADER_SIZE;
+
+/// Where the encoder puts things in the video, enough to tell which frames
+/// hold a given byte of the stream.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamLayout {
+    /// Stream bytes per chunk
+    pub payload_size: u64,
+    pub total_shards: u64,
+    /// Chunks between header copies, 0 for none
+    pub header_interval: u64,
+    /// Video frame of the first data frame, after the header and index frames
+    pub first_data_frame: u64,
+}
+
+impl StreamLayout {
+    pub fn chunk_of(&self, offset: u64) -> u64 {
+        offset / self.payload_size
+    }
+
+    /// Video frame carrying shard `shard` of chunk `chunk`, counting every
+    /// frame from the first header.
+    pub fn frame_of(&self, chunk: u64, shard: u64) -> u64 {
+        let header_copies = match self.header_interval {
+            0 => 0,
+            interval => chunk / interval,
+        };
+        self.first_data_frame + header_copies + chunk * self.total_shards + shard
+    }
+}
+
+/// One archived file: where it is in the stream and in the video.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub path: String,
+    /// Byte range in the (uncompressed) stream
+    pub offset: u64,
+    pub size: u64,
+    /// Chunks holding those bytes, inclusive
+    pub first_chunk: u64,
+    pub last_chunk: u64,
+    /// Video frames holding those chunks, inclusive, header copies in between included
+    pub first_frame: u64,
+    pub last_frame: u64,
+    pub sha256: [u8; 32],
+}
+
+/// Written in its own bootstrap frames right after the first header, so
+/// `extract` can find a file without decoding everything before it. In an
+/// encrypted video it is sealed like a chunk, since it lists the file names.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Index {
+    pub entries: Vec<IndexEntry>,
+}
+
+impl Index {
+    pub fn new(manifest: &Manifest, layout: &StreamLayout) -> Result<Self> {
+        let mut offset = manifest.to_bytes()?.len() as u64;
+        let mut entries = Vec::new();
+        for entry in manifest.entries.iter() {
+            let Some(hash) = &entry.sha256 else {
+                continue;
+            };
+            let first_chunk = layout.chunk_of(offset);
+            let last_chunk = layout.chunk_of((offset + entry.size).max(1) - 1).max(first_chunk);
+            entries.push(IndexEntry {
+                path: entry.path.clone(),
+                offset,
+                size: entry.size,
+                first_chunk,
+                last_chunk,
+                first_frame: layout.frame_of(first_chunk, 0),
+                last_frame: layout.frame_of(last_chunk, layout.total_shards - 1),
+                sha256: crate::utils::from_hex(hash)?,
+            });
+            offset += entry.size;
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn find(&self, path: &str) -> Option<&IndexEntry> {
+        let path = path.trim_matches('/');
+        self.entries.iter().find(|e| e.path == path)
+    }
+
+    /// How many frames `to_frames` will need, known before the frame numbers are.
+    pub fn frame_count(&self, encrypted: bool) -> Result<usize> {
+        let overhead = if encrypted { TAG_SIZE } else { 0 };
+        Ok((self.to_bytes()?.len() + overhead).div_ceil(PART_CAPACITY).max(1))
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        bytes.write_u32::<BigEndian>(self.entries.len().try_into().context("Too many files to index")?)?;
+        for entry in &self.entries {
+            bytes.write_u16::<BigEndian>(entry.path.len().try_into().context("Path too long to index")?)?;
+            bytes.extend_from_slice(entry.path.as_bytes());
+            for value in [entry.offset, entry.size, entry.first_chunk, entry.last_chunk, entry.first_frame, entry.last_frame] {
+                bytes.write_u64::<BigEndian>(value)?;
+            }
+            bytes.extend_from_slice(&entry.sha256);
+        }
+        Ok(bytes)
+    }
+
+    fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let count = bytes.read_u32::<BigEndian>()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let mut path = vec![0u8; bytes.read_u16::<BigEndian>()? as usize];
+            bytes.read_exact(&mut path)?;
+            let mut values = [0u64; 6];
+            for value in values.iter_mut() {
+                *value = bytes.read_u64::<BigEndian>()?;
+            }
+            let [offset, size, first_chunk, last_chunk, first_frame, last_frame] = values;
+            let mut sha256 = [0u8; 32];
+            bytes.read_exact(&mut sha256)?;
+            entries.push(IndexEntry {
+                path: String::from_utf8(path).context("Invalid UTF-8 in index")?,
+                offset,
+                size,
+                first_chunk,
+                last_chunk,
+                first_frame,
+                last_frame,
+                sha256,
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    /// Bootstrap payloads, one per index frame
+    pub fn to_frames(&self, cipher: Option<&ChunkCipher>) -> Result<Vec<Vec<u8>>> {
+        let mut bytes = self.to_bytes()?;
+        if let Some(cipher) = cipher {
+            cipher.seal(INDEX_NONCE, &mut bytes)?;
+        }
+        let parts = self.frame_count(cipher.is_some())?;
+        (0..parts)
+            .map(|part| {
+                let data = &bytes[(part * PART_CAPACITY).min(bytes.len())..((part + 1) * PART_CAPACITY).min(bytes.len())];
+                let mut payload = Vec::with_capacity(HEADER_SIZE);
+                payload.write_u64::<BigEndian>(INDEX_MAGIC)?;
+                payload.write_u32::<BigEndian>(part as u32)?;
+                payload.write_u32::<BigEndian>(parts as u32)?;
+                payload.write_u16::<BigEndian>(data.len() as u16)?;
+                payload.extend_from_slice(data);
+                payload.resize(HEADER_SIZE, 0);
+                Ok(payload)
+            })
+            .collect()
+    }
+
+    /// Puts the index back together from its frames' payloads, in order.
+    pub fn from_frames(payloads: &[Vec<u8>], cipher: Option<&ChunkCipher>) -> Result<Self> {
+        let mut bytes = Vec::new();
+        for (expected, payload) in payloads.iter().enumerate() {
+            let (part, parts, data) = read_part(payload)?;
+            if part != expected as u32 || parts != payloads.len() as u32 {
+                return Err(anyhow::anyhow!("Index frame {} of {} out of place", part, parts));
+            }
+            bytes.extend_from_slice(data);
+        }
+        if let Some(cipher) = cipher {
+            cipher.open(INDEX_NONCE, &mut bytes).context("Index failed authentication")?;
+        }
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Whether a bootstrap payload is an index frame
+pub fn is_index_frame(payload: &[u8]) -> bool {
+    read_part(payload).is_ok()
+}
+
+fn read_part(mut payload: &[u8]) -> Result<(u32, u32, &[u8])> {
+    if payload.read_u64::<BigEndian>()? != INDEX_MAGIC {
+        return Err(anyhow::anyhow!("Not an index frame"));
+    }
+    let part = payload.read_u32::<BigEndian>()?;
+    let parts = payload.read_u32::<BigEndian>()?;
+    let len = payload.read_u16::<BigEndian>()? as usize;
+    let data = payload.get(..len).context("Index frame truncated")?;
+    Ok((part, parts, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::{Entry, EntryKind};
+    use crate::crypto::{Identity, Recipients};
+    use crate::metadata::Metadata;
+
+    #[test]
+    fn test_index_locates_files() {
+        let file = |path: &str, size: u64| Entry {
+            path: path.to_string(),
+            kind: EntryKind::File,
+            size,
+            metadata: Metadata { mode: 0o644, ..Default::default() },
+            sha256: Some(format!("{:064x}", size)),
+        };
+        let mut manifest = Manifest { entries: Vec::new() };
+        manifest.entries.push(Entry { kind: EntryKind::Directory, sha256: None, ..file("d", 0) });
+        for i in 0..60 {
+            manifest.entries.push(file(&format!("d/file-with-a-longish-name-{}", i), 100 + i * 50));
+        }
+        manifest.entries.push(file("d/empty", 0));
+        let layout = StreamLayout { payload_size: 1000, total_shards: 3, header_interval: 4, first_data_frame: 3 };
+        let index = Index::new(&manifest, &layout).unwrap();
+
+        let frames = index.to_frames(None).unwrap();
+        assert_eq!(frames.len(), index.frame_count(false).unwrap());
+        assert!(frames.len() > 1 && frames.iter().all(|f| f.len() == HEADER_SIZE && is_index_frame(f)));
+        assert_eq!(Index::from_frames(&frames, None).unwrap(), index);
+        assert!(Index::from_frames(&frames[1..], None).is_err());
+
+        // Sealed, the file names don't show and only the key opens it
+        let (_, cipher) = Recipients::new(&[Identity::generate().unwrap().recipient()]).unwrap();
+        let sealed = index.to_frames(Some(&cipher)).unwrap();
+        assert_eq!(sealed.len(), index.frame_count(true).unwrap());
+        assert!(!sealed.concat().windows(4).any(|w| w == b"long"));
+        assert_eq!(Index::from_frames(&sealed, Some(&cipher)).unwrap(), index);
+        assert!(Index::from_frames(&sealed, None).is_err());
+
+        let first = index.find("/d/file-with-a-longish-name-0").unwrap();
+        let start = manifest.to_bytes().unwrap().len() as u64;
+        assert_eq!((first.offset, first.size), (start, 100));
+        assert_eq!(first.first_chunk, start / 1000);
+        // Chunk 4 comes after a header copy: 3 + 1 + 4 * 3
+        assert_eq!(layout.frame_of(4, 0), 16);
+        let last = index.find("d/file-with-a-longish-name-59").unwrap();
+        assert_eq!(last.last_chunk, (last.offset + last.size - 1) / 1000);
+        assert_eq!(last.last_frame, layout.frame_of(last.last_chunk, 2));
+        assert_eq!(index.find("d/empty").unwrap().size, 0);
+        assert!(index.find("d").is_none());
+    }
+}
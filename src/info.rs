@@ -0,0 +1,215 @@
+use crate::metadata::Metadata;
+use crate::signing::SignatureStatus;
+use crate::utils::{FileHeader, FileTrailer};
+use anyhow::Result;
+use serde::Serialize;
+
+/// What `info` reports about a video, from its header, its trailer if it
+/// was found and the frame count of the container.
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoInfo {
+    pub format_version: String,
+    pub original_filename: String,
+    pub file_size: u64,
+    /// Known once the trailer or a final header copy was read
+    pub sha256_hash: Option<String>,
+    pub resolution: String,
+    pub fps: u32,
+    pub block_size: String,
+    pub bits_per_block: u8,
+    pub color: bool,
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    /// Chunks between header copies, if the encoder recorded it
+    pub header_interval: Option<u64>,
+    /// `passphrase` or `recipients`
+    pub encryption: Option<String>,
+    pub recipients: Option<usize>,
+    pub compression_level: Option<i32>,
+    pub archive: bool,
+    pub index_frames: Option<u32>,
+    pub metadata: Option<Metadata>,
+    /// Bytes per data or parity frame
+    pub frame_capacity: usize,
+    /// Input bytes per chunk
+    pub chunk_payload: usize,
+    /// From the trailer, or from the file size unless it was compressed
+    pub total_chunks: Option<u64>,
+    /// Parity shards per data shard
+    pub parity_overhead: f64,
+    pub expected_frames: Option<u64>,
+    /// As counted by ffprobe
+    pub present_frames: Option<u64>,
+    pub duration_seconds: Option<f64>,
+    /// Input bytes per byte the frames can hold, header and parity frames included
+    pub efficiency: Option<f64>,
+    pub trailer: bool,
+    /// `none`, `invalid` or `valid`. Only the header and trailer are
+    /// checked, not the chunks against the Merkle root.
+    pub signature: String,
+    pub signer: Option<String>,
+}
+
+impl VideoInfo {
+    pub fn new(header: &FileHeader, final_hash: Option<String>, trailer: Option<&FileTrailer>, present_frames: Option<u64>) -> Result<Self> {
+        let params = header.params()?;
+        let payload_size = header.payload_size()?;
+        let compression = header.compression()?;
+        let header_interval = header.header_interval()?;
+        let index_frames = header.index_frames()?;
+
+        let total_chunks = match (trailer, compression) {
+            (Some(trailer), _) => Some(trailer.total_chunks),
+            (None, None) => Some(header.file_size.div_ceil(payload_size as u64)),
+            (None, Some(_)) => None,
+        };
+        // Header, index, the shards of every chunk with header copies in
+        // between, final header and trailer
+        let expected_frames = match (total_chunks, header_interval) {
+            (Some(chunks), Some(interval)) => {
+                let copies = chunks.saturating_sub(1).checked_div(interval).unwrap_or(0);
+                let shards = chunks * params.total_shards() as u64;
+                Some(1 + index_frames.unwrap_or(0) as u64 + shards + copies + 2)
+            }
+            _ => None,
+        };
+        let frames = present_frames.or(expected_frames);
+
+        let (signature, signer) = match trailer.map(|t| SignatureStatus::check(header, t)) {
+            Some(SignatureStatus::Valid(signer)) => ("valid", Some(signer.to_string())),
+            Some(SignatureStatus::Invalid) => ("invalid", None),
+            _ => ("none", None),
+        };
+        let recipients = header.recipients()?.map(|r| r.len());
+
+        Ok(Self {
+            format_version: format!("{}.{}", header.version, header.minor_version),
+            original_filename: header.original_filename.clone(),
+            file_size: header.file_size,
+            sha256_hash: trailer.map(|t| t.sha256_hash.clone()).or(final_hash),
+            resolution: header.geometry().to_string(),
+            fps: header.fps,
+            block_size: header.block_size().to_string(),
+            bits_per_block: header.bits_per_block,
+            color: header.color,
+            data_shards: header.data_shards,
+            parity_shards: header.parity_shards,
+            header_interval,
+            encryption: match (header.encryption()?, recipients) {
+                (Some(_), _) => Some("passphrase".to_string()),
+                (None, Some(_)) => Some("recipients".to_string()),
+                (None, None) => None,
+            },
+            recipients,
+            compression_level: compression.map(|c| c.level),
+            archive: header.is_archive()?,
+            index_frames,
+            metadata: header.metadata()?,
+            frame_capacity: params.shard_size(),
+            chunk_payload: payload_size,
+            total_chunks,
+            parity_overhead: header.parity_shards as f64 / header.data_shards as f64,
+            expected_frames,
+            present_frames,
+            duration_seconds: frames.map(|frames| frames as f64 / header.fps as f64),
+            efficiency: frames.map(|frames| header.file_size as f64 / (frames as f64 * params.shard_size() as f64)),
+            trailer: trailer.is_some(),
+            signature: signature.to_string(),
+            signer,
+        })
+    }
+
+    pub fn print(&self) {
+        let or_unknown = |value: Option<String>| value.unwrap_or_else(|| "unknown".to_string());
+        println!("Format Version: {}", self.format_version);
+        println!("Original Filename: {}", self.original_filename);
+        println!("File Size: {} bytes", self.file_size);
+        println!("SHA-256: {}", or_unknown(self.sha256_hash.clone()));
+        println!("Resolution: {} @ {} fps", self.resolution, self.fps);
+        println!("Block Size: {}", self.block_size);
+        println!("Bits per Block: {}{}", self.bits_per_block, if self.color { " per channel (colour)" } else { "" });
+        println!("RS Configuration: {} data / {} parity", self.data_shards, self.parity_shards);
+        println!("Header Interval: {}", or_unknown(self.header_interval.map(|i| i.to_string())));
+        match (&self.encryption, self.recipients) {
+            (Some(_), Some(recipients)) => println!("Encryption: to {} recipient(s)", recipients),
+            (Some(_), None) => println!("Encryption: passphrase"),
+            (None, _) => println!("Encryption: none"),
+        }
+        if let Some(level) = self.compression_level {
+            println!("Compression: zstd level {}", level);
+        }
+        if self.archive {
+            println!("Archive: yes, {} index frame(s)", self.index_frames.unwrap_or(0));
+        }
+        if let Some(metadata) = &self.metadata {
+            println!("Permissions: {:o}, modified {} (Unix time)", metadata.mode, metadata.mtime);
+        }
+        println!("Frame Capacity: {} bytes", self.frame_capacity);
+        println!("Chunk Payload: {} bytes", self.chunk_payload);
+        println!("Chunks: {}", or_unknown(self.total_chunks.map(|c| c.to_string())));
+        println!("Parity Overhead: {:.0}%", self.parity_overhead * 100.0);
+        println!(
+            "Frames: {} present, {} expected",
+            or_unknown(self.present_frames.map(|f| f.to_string())),
+            or_unknown(self.expected_frames.map(|f| f.to_string()))
+        );
+        if let (Some(present), Some(expected)) = (self.present_frames, self.expected_frames) {
+            if present < expected {
+                println!("WARNING: {} frame(s) missing", expected - present);
+            } else if present > expected {
+                println!("WARNING: {} frame(s) more than expected, a host may have duplicated some", present - expected);
+            }
+        }
+        if let Some(duration) = self.duration_seconds {
+            println!("Duration: {:.1} s", duration);
+        }
+        if let Some(efficiency) = self.efficiency {
+            println!("Efficiency: {:.1}% of the frame capacity holds input", efficiency * 100.0);
+        }
+        println!("Trailer: {}", if self.trailer { "found" } else { "not found" });
+        match &self.signer {
+            Some(signer) => println!("Signature: {}, signed by {}", self.signature, signer),
+            None => println!("Signature: {}", self.signature),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::BlockSize;
+    use crate::modulation::Modulation;
+    use crate::utils::{EncodingParams, Geometry, DEFAULT_FPS, EXT_HEADER_INTERVAL};
+
+    #[test]
+    fn test_expected_frames() {
+        let params = EncodingParams {
+            geometry: Geometry::new(640, 360),
+            fps: DEFAULT_FPS,
+            block_size: BlockSize::square(4),
+            modulation: Modulation::BINARY,
+            data_shards: 10,
+            parity_shards: 2,
+        };
+        let chunk_size = params.chunk_size() as u64;
+        // 33 chunks, the last one partial
+        let mut header = FileHeader::new("a.bin".to_string(), chunk_size * 32 + 1, "PENDING".to_string(), &params);
+
+        let info = VideoInfo::new(&header, None, None, Some(400)).unwrap();
+        assert_eq!(info.total_chunks, Some(33));
+        assert_eq!(info.expected_frames, None);
+
+        // Copies before chunks 16 and 32
+        header.set_extension(EXT_HEADER_INTERVAL, 16u64.to_be_bytes().to_vec());
+        let info = VideoInfo::new(&header, None, None, Some(400)).unwrap();
+        assert_eq!(info.expected_frames, Some(1 + 33 * 12 + 2 + 2));
+        assert_eq!(info.duration_seconds, Some(400.0 / 30.0));
+
+        header.set_extension(EXT_HEADER_INTERVAL, 0u64.to_be_bytes().to_vec());
+        let trailer = FileTrailer::new("00".repeat(32), 33, header.file_size);
+        let info = VideoInfo::new(&header, None, Some(&trailer), None).unwrap();
+        assert_eq!(info.expected_frames, Some(1 + 33 * 12 + 2));
+        assert_eq!(info.sha256_hash, Some("00".repeat(32)));
+        assert_eq!(info.signature, "none");
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Size of one block in pixels. Blocks need not be square.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSize {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl BlockSize {
+    pub const fn square(size: usize) -> Self {
+        Self { width: size, height: size }
+    }
+
+    pub fn is_square(&self) -> bool {
+        self.width == self.height
+    }
+}
+
+impl fmt::Display for BlockSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl FromStr for BlockSize {
+    type Err = anyhow::Error;
+
+    /// Either a single size for square blocks (`4`) or `WIDTHxHEIGHT` (`8x4`).
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let block = match s.split_once('x') {
+            Some((w, h)) => Self {
+                width: w.parse().context("Invalid block width")?,
+                height: h.parse().context("Invalid block height")?,
+            },
+            None => Self::square(s.parse().context("Invalid block size")?),
+        };
+        if block.width == 0 || block.height == 0 {
+            return Err(anyhow::anyhow!("Block size must be non-zero"));
+        }
+        Ok(block)
+    }
+}
+
+/// Cells along a side of `length` pixels, counting a partial cell at the end
+/// if it is at least half a block.
+fn cells_along(length: usize, block: usize) -> usize {
+    let remainder = length % block;
+    length / block + usize::from(remainder > 0 && remainder * 2 >= block)
+}
+
+/// The canonical frame layout. A band of a frame (the bootstrap data area, the
+/// header strip or the body of a data frame), `rows` pixel rows (in encoded or
+/// grid coordinates) across the full `width`, is cut into cells of one block
+/// size, row by row from the top left. Symbols fill the cells in that order,
+/// one per cell in gray mode or one per channel (R, G, B) in colour mode, and
+/// bits fill the symbols MSB first, running on from one row to the next.
+///
+/// Cells left over at the right and bottom edges are used as well, as long as
+/// they are at least half a block wide (or high); anything thinner would be
+/// the first casualty of a lossy codec and stays a blank margin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    width: usize,
+    rows: Range<usize>,
+    block: BlockSize,
+    cols: usize,
+    cell_rows: usize,
+}
+
+impl Layout {
+    pub fn new(width: usize, rows: Range<usize>, block: BlockSize) -> Self {
+        Self {
+            cols: cells_along(width, block.width),
+            cell_rows: cells_along(rows.len(), block.height),
+            width,
+            rows,
+            block,
+        }
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn cell_rows(&self) -> usize {
+        self.cell_rows
+    }
+
+    pub fn cells(&self) -> usize {
+        self.cols * self.cell_rows
+    }
+
+    /// Bytes that fit in the band at `bits_per_cell`.
+    pub fn capacity(&self, bits_per_cell: usize) -> usize {
+        self.cells() * bits_per_cell / 8
+    }
+
+    /// Pixel columns and rows covered by cell `index`. Edge cells are cut
+    /// short by the border of the band.
+    pub fn cell(&self, index: usize) -> (Range<usize>, Range<usize>) {
+        let (col, row) = (index % self.cols, index / self.cols);
+        let x = col * self.block.width;
+        let y = self.rows.start + row * self.block.height;
+        (
+            x..(x + self.block.width).min(self.width),
+            y..(y + self.block.height).min(self.rows.end),
+        )
+    }
+
+    /// Index of the cell covering pixel (`x`, `y`), if any.
+    pub fn cell_at(&self, x: usize, y: usize) -> Option<usize> {
+        let y = y.checked_sub(self.rows.start)?;
+        let (col, row) = (x / self.block.width, y / self.block.height);
+        (col < self.cols && row < self.cell_rows).then_some(row * self.cols + col)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_size_parsing() {
+        assert_eq!("4".parse::<BlockSize>().unwrap(), BlockSize::square(4));
+        assert_eq!("8x4".parse::<BlockSize>().unwrap(), BlockSize { width: 8, height: 4 });
+        assert!("0".parse::<BlockSize>().is_err());
+        assert!("8x".parse::<BlockSize>().is_err());
+    }
+
+    #[test]
+    fn test_edge_cells() {
+        // 1920 = 274 * 7 + 2: a 2px sliver is too thin to use
+        let layout = Layout::new(1920, 0..1080, BlockSize::square(7));
+        assert_eq!(layout.cols(), 274);
+        assert_eq!(layout.cell_at(1919, 0), None);
+
+        // 1920 = 213 * 9 + 3, 1080 = 120 * 9: no usable partial column either
+        assert_eq!(Layout::new(1920, 0..1080, BlockSize::square(9)).cols(), 213);
+
+        // 1920 = 174 * 11 + 6: the 6px column is used, cut short at the edge
+        let layout = Layout::new(1920, 100..1080, BlockSize::square(11));
+        assert_eq!(layout.cols(), 175);
+        assert_eq!(layout.cells(), 175 * 89); // 980 = 89 * 11 + 1
+        let (xs, ys) = layout.cell(174);
+        assert_eq!((xs, ys), (1914..1920, 100..111));
+        assert_eq!(layout.cell_at(1919, 105), Some(174));
+        assert_eq!(layout.cell_at(0, 99), None);
+
+        // Every pixel of a cell maps back to it
+        let layout = Layout::new(1280, 48..720, BlockSize { width: 6, height: 5 });
+        for index in [0, 1, layout.cols(), layout.cells() - 1] {
+            let (xs, ys) = layout.cell(index);
+            for y in ys {
+                for x in xs.clone() {
+                    assert_eq!(layout.cell_at(x, y), Some(index));
+                }
+            }
+        }
+    }
+}
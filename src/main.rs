@@ -1,11 +1,40 @@
 mod utils;
+mod archive;
+mod metadata;
+mod bootstrap;
+mod compression;
+mod crypto;
+mod signing;
+mod index;
+mod info;
+mod scrub;
+mod calibration;
+mod layout;
+mod modulation;
+mod sampling;
 mod encoder;
 mod decoder;
+mod repair;
+#[cfg(test)]
+mod vectors;
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use encoder::Encoder;
-use decoder::Decoder;
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand};
+use crypto::{Identity, Recipient};
+use encoder::{EncodeOptions, Encoder};
+use decoder::{ChunkStatus, DecodeOptions, Decoder, Destination, HashCheck};
+use repair::Repair;
+use layout::BlockSize;
+use metadata::Restore;
+use modulation::Modulation;
+use sampling::Sampling;
+use signing::{SignKey, SignatureStatus, VerifyKey};
+use utils::{EncodingParams, Geometry, DEFAULT_FPS, DEFAULT_HEADER_INTERVAL};
+
+// Exit codes of `verify`, for cron jobs and monitoring. Errors exit with 1.
+const EXIT_CORRUPT: i32 = 2;
+const EXIT_DEGRADED: i32 = 3;
+const EXIT_UNVERIFIED: i32 = 4;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -19,17 +48,17 @@ struct Cli {
 enum Commands {
     /// Encode a file into a video
     Encode {
-        /// Input file path
-        #[arg(short, long)]
-        input: String,
+        /// Input file path. Directories, or several inputs (repeat -i), are stored as an archive.
+        #[arg(short, long, required = true)]
+        input: Vec<String>,
 
         /// Output video path (e.g., output.mkv)
         #[arg(short, long)]
         output: String,
 
-        /// Block size (bigger = less storage density, more robust against compression). Default 4.
-        #[arg(short, long, default_value_t = 4)]
-        block_size: usize,
+        /// Block size (bigger = less storage density, more robust against compression). Default 4. WIDTHxHEIGHT (e.g. 8x4) for non-square blocks.
+        #[arg(short, long, default_value = "4")]
+        block_size: BlockSize,
 
         /// Data shards for Reed-Solomon (Default 10)
         #[arg(long, default_value_t = 10)]
@@ -42,6 +71,54 @@ enum Commands {
         /// Ffmpeg codec to use (Default "ffv1" for lossless, or "libx264")
         #[arg(long, default_value = "ffv1")]
         codec: String,
+
+        /// Output resolution as WIDTHxHEIGHT, e.g. 1280x720 or 1080x1920 for vertical video
+        #[arg(long, default_value = "1920x1080")]
+        resolution: Geometry,
+
+        /// Output frame rate (Default 30)
+        #[arg(long, default_value_t = DEFAULT_FPS)]
+        fps: u32,
+
+        /// Bits stored per block as 2^n gray levels (1-4), per channel with --color. Above 1 only suits lossless codecs like ffv1.
+        #[arg(long, default_value_t = 1)]
+        bits_per_block: u8,
+
+        /// Modulate R, G and B independently (3x the bits per block). Needs an RGB lossless codec such as ffv1 or libx264rgb.
+        #[arg(long)]
+        color: bool,
+
+        /// Repeat the header frame every N chunks, so a video missing its start can still be decoded. 0 only writes it at the start and end.
+        #[arg(long, default_value_t = DEFAULT_HEADER_INTERVAL)]
+        header_interval: u64,
+
+        /// Encrypt the data with a passphrase (XChaCha20-Poly1305, Argon2id key). Asked for on the terminal unless --passphrase-file is given.
+        #[arg(long)]
+        encrypt: bool,
+
+        /// Read the passphrase from the first line of this file
+        #[arg(long, requires = "encrypt")]
+        passphrase_file: Option<String>,
+
+        /// Encrypt to this public key (B2V-PUBLIC-KEY-...), see `keygen`. Repeat for several recipients.
+        #[arg(short, long, conflicts_with = "encrypt")]
+        recipient: Vec<Recipient>,
+
+        /// Encrypt to every public key listed in this file, one per line
+        #[arg(long, conflicts_with = "encrypt")]
+        recipients_file: Vec<String>,
+
+        /// Sign the video with this signing key (see `keygen --sign`), so `verify` can prove where it came from
+        #[arg(long)]
+        sign_key: Option<String>,
+
+        /// Compress the input with zstd first, at the given level (1-22, 3 if omitted). Skipped if a sample of the input doesn't compress.
+        #[arg(long, value_name = "LEVEL", num_args = 0..=1, default_missing_value = "3", value_parser = clap::value_parser!(i32).range(1..=22))]
+        compress: Option<i32>,
+
+        /// Also record extended attributes (Unix), besides permissions and times
+        #[arg(long)]
+        xattrs: bool,
     },
     /// Decode a video back to file
     Decode {
@@ -49,19 +126,219 @@ enum Commands {
         #[arg(short, long)]
         input: String,
 
-        /// Output file path
+        /// Output file path, or the directory to restore an archive into (default: the stored file name, or the current directory for an archive)
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Also restore recorded extended attributes, of the user namespace only
+        #[arg(long)]
+        xattrs: bool,
+
+        /// Also restore the setuid, setgid and sticky bits
+        #[arg(long)]
+        preserve_setid: bool,
+
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Decode a video without writing it out, checking its hash and signature
+    Verify {
+        /// Input video path
+        #[arg(short, long)]
+        input: String,
+
+        /// Only accept videos signed by this verify key (B2V-VERIFY-KEY-...). Repeat for several. Without it, signature problems are only warnings.
+        #[arg(long)]
+        trusted_key: Vec<VerifyKey>,
+
+        /// Report the video as degraded if any chunk has fewer parity shards than this to spare
+        #[arg(long, default_value_t = 0)]
+        min_margin: usize,
+
+        /// Also write the full report, chunk by chunk, to this JSON file
+        #[arg(long)]
+        report: Option<String>,
+
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Extract one file of an archive, reading only the frames that hold it
+    Extract {
+        /// Input video path
+        #[arg(short, long)]
+        input: String,
+
+        /// Path of the file in the archive, starting with the input it was stored from (e.g. photos/2020/a.jpg)
+        #[arg(long)]
+        file: String,
+
+        /// Output file path (default: the file's name, in the current directory)
+        #[arg(short, long)]
+        output: Option<String>,
+
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Measure how close each frame is to being misread: bit error rates, margins from the level thresholds and the weakest regions
+    Scrub {
+        /// Input video path
+        #[arg(short, long)]
+        input: String,
+
+        /// Write the full report, with every frame, region and the margin histogram, to this JSON file
+        #[arg(long)]
+        json: Option<String>,
+
+        /// Write one row per frame to this CSV file
+        #[arg(long)]
+        csv: Option<String>,
+
+        #[command(flatten)]
+        read: ReadArgs,
+    },
+    /// Show what a video holds and how, reading only its header and trailer frames
+    Info {
+        /// Input video path
+        #[arg(short, long)]
+        input: String,
+
+        /// Print JSON instead, for scripts and catalogues
+        #[arg(long)]
+        json: bool,
+    },
+    /// Write a fresh video from a damaged one, rebuilding lost shards on the way. The data never touches the disk.
+    Repair {
+        /// Damaged video path
+        #[arg(short, long)]
+        input: String,
+
+        /// New video path
         #[arg(short, long)]
         output: String,
+
+        /// Block size of the new video (default: that of the damaged one)
+        #[arg(short, long)]
+        block_size: Option<BlockSize>,
+
+        /// Data shards (default: as before)
+        #[arg(long)]
+        data_shards: Option<usize>,
+
+        /// Parity shards (default: as before)
+        #[arg(long)]
+        parity_shards: Option<usize>,
+
+        /// Ffmpeg codec to use
+        #[arg(long, default_value = "ffv1")]
+        codec: String,
+
+        /// Resolution as WIDTHxHEIGHT (default: as before)
+        #[arg(long)]
+        resolution: Option<Geometry>,
+
+        /// Frame rate (default: as before)
+        #[arg(long)]
+        fps: Option<u32>,
+
+        /// Bits stored per block per channel, 1-4 (default: as before)
+        #[arg(long)]
+        bits_per_block: Option<u8>,
+
+        /// Modulate R, G and B independently, true or false (default: as before)
+        #[arg(long)]
+        color: Option<bool>,
+
+        /// Repeat the header frame every N chunks (default: as before)
+        #[arg(long)]
+        header_interval: Option<u64>,
+
+        /// Compress at this level (default: as before). A stream can't be sampled first, so it is compressed regardless.
+        #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(1..=22))]
+        compress: Option<i32>,
+
+        /// Don't compress the new video, even if the damaged one was
+        #[arg(long, conflicts_with = "compress")]
+        no_compress: bool,
+
+        /// Encrypt to this public key instead. Needed for videos encrypted to public keys, which can't be read back from them. Repeat for several recipients.
+        #[arg(short, long)]
+        recipient: Vec<Recipient>,
+
+        /// Encrypt to every public key listed in this file, one per line
+        #[arg(long)]
+        recipients_file: Vec<String>,
+
+        /// Sign the new video with this signing key. The damaged video's signature doesn't carry over.
+        #[arg(long)]
+        sign_key: Option<String>,
+
+        #[command(flatten)]
+        read: ReadArgs,
     },
+    /// Generate a key pair for encrypting to public keys
+    Keygen {
+        /// Where to write the secret key. Its public key is printed and kept in a comment.
+        #[arg(short, long)]
+        output: String,
+
+        /// Generate a signing key for `encode --sign-key` instead; its verify key is printed
+        #[arg(long)]
+        sign: bool,
+    },
+}
+
+/// How to read a video, shared by the commands that decode one
+#[derive(Args)]
+struct ReadArgs {
+    /// How each block is read: centre pixel, or the mean, median or majority of its interior
+    #[arg(long, value_enum, default_value_t = Sampling::Mean)]
+    sampling: Sampling,
+
+    /// Treat shards with any bit read below this confidence (0-100%) as erasures, even if their CRC matches. 0 trusts the CRC alone.
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    min_confidence: u8,
+
+    /// Use the levels measured on the header frame's calibration patches as they are, without adapting them to each frame
+    #[arg(long)]
+    fixed_levels: bool,
+
+    /// Read the passphrase of an encrypted video from the first line of this file, instead of asking for it
+    #[arg(long)]
+    passphrase_file: Option<String>,
+
+    /// Secret key file for videos encrypted to public keys. Repeat to try several.
+    #[arg(long)]
+    identity: Vec<String>,
+}
+
+impl ReadArgs {
+    fn options(&self) -> Result<DecodeOptions> {
+        println!("Sampling: {:?}", self.sampling);
+        Ok(DecodeOptions {
+            sampling: self.sampling,
+            min_confidence: (self.min_confidence as u32 * 255 / 100) as u8,
+            fixed_levels: self.fixed_levels,
+            passphrase: self.passphrase_file.as_deref().map(crypto::read_passphrase_file).transpose()?,
+            identities: self
+                .identity
+                .iter()
+                .map(|path| Identity::read_file(path))
+                .collect::<Result<Vec<_>>>()?
+                .concat(),
+            restore: Restore::default(),
+        })
+    }
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    // Print banner
-    println!("Eternal-Stream v0.1.0");
-    println!("Spiritual successor to Infinite Storage Glitch - Enterprise Grade");
-    println!("===============================================================");
+    // Print banner, unless the output is meant for another program
+    if !matches!(cli.command, Commands::Info { json: true, .. }) {
+        println!("Eternal-Stream v0.1.0");
+        println!("Spiritual successor to Infinite Storage Glitch - Enterprise Grade");
+        println!("===============================================================");
+    }
 
     match &cli.command {
         Commands::Encode { 
@@ -70,32 +347,259 @@ fn main() -> Result<()> {
             block_size, 
             data_shards, 
             parity_shards,
-            codec 
+            codec,
+            resolution,
+            fps,
+            bits_per_block,
+            color,
+            header_interval,
+            encrypt,
+            passphrase_file,
+            recipient,
+            recipients_file,
+            sign_key,
+            compress,
+            xattrs,
         } => {
             println!("Mode: ENCODE");
-            println!("Input: {}", input);
+            println!("Input: {}", input.join(", "));
             println!("Output: {}", output);
             println!("Block Size: {}", block_size);
             println!("RS Configuration: {} data / {} parity", data_shards, parity_shards);
             println!("Codec: {}", codec);
+            println!("Resolution: {} @ {} fps", resolution, fps);
+            println!("Bits per Block: {}{}", bits_per_block, if *color { " per channel (colour)" } else { "" });
             
+            let params = EncodingParams {
+                geometry: *resolution,
+                fps: *fps,
+                block_size: *block_size,
+                modulation: Modulation::new(*bits_per_block, *color)?,
+                data_shards: *data_shards,
+                parity_shards: *parity_shards,
+            };
+            let passphrase = match (encrypt, passphrase_file) {
+                (true, Some(path)) => Some(crypto::read_passphrase_file(path)?),
+                (true, None) => Some(crypto::prompt_passphrase(true)?),
+                (false, _) => None,
+            };
+            let mut recipients = recipient.clone();
+            for path in recipients_file {
+                recipients.extend(crypto::read_recipients_file(path)?);
+            }
+            let options = EncodeOptions {
+                codec: codec.clone(),
+                header_interval: *header_interval,
+                passphrase,
+                recipients,
+                sign_key: sign_key.as_deref().map(SignKey::read_file).transpose()?,
+                compression_level: *compress,
+                xattrs: *xattrs,
+            };
             let encoder = Encoder::new(
                 input.clone(),
                 output.clone(),
-                *block_size,
-                *data_shards,
-                *parity_shards,
-                codec.clone(),
+                params,
+                options,
             );
             encoder.run()?;
         }
-        Commands::Decode { input, output } => {
+        Commands::Decode { input, output, xattrs, preserve_setid, read } => {
             println!("Mode: DECODE");
             println!("Input: {}", input);
+            let destination = match output {
+                Some(output) => {
+                    println!("Output: {}", output);
+                    Destination::Path(output.clone())
+                }
+                None => Destination::Stored,
+            };
+
+            let options = DecodeOptions { restore: Restore { xattrs: *xattrs, setid: *preserve_setid }, ..read.options()? };
+            let decoder = Decoder::new(input.clone(), destination, options);
+            decoder.run()?.check_hash()?;
+        }
+        Commands::Verify { input, trusted_key, min_margin, report: report_path, read } => {
+            println!("Mode: VERIFY");
+            println!("Input: {}", input);
+
+            let decoder = Decoder::new(input.clone(), Destination::Verify, read.options()?);
+            let report = decoder.run()?;
+            if let Some(path) = report_path {
+                std::fs::write(path, serde_json::to_string_pretty(&report)?)
+                    .with_context(|| format!("Failed to write report {}", path))?;
+            }
+
+            let problem = match &report.signature {
+                SignatureStatus::Valid(signer) if trusted_key.is_empty() || trusted_key.contains(signer) => None,
+                SignatureStatus::Valid(signer) => Some(format!("Video is signed by an untrusted key: {}", signer)),
+                SignatureStatus::Invalid => Some("Video has an invalid signature".to_string()),
+                SignatureStatus::Unsigned => Some("Video is not signed".to_string()),
+            };
+            // Worst first
+            let unrecoverable = report.count(ChunkStatus::Unrecoverable);
+            let status = if let HashCheck::Mismatch { expected, calculated } = &report.hash {
+                eprintln!("CORRUPT: Hash mismatch! Expected {}, got {}", expected, calculated);
+                EXIT_CORRUPT
+            } else if unrecoverable > 0 {
+                eprintln!("CORRUPT: {} chunk(s) unrecoverable", unrecoverable);
+                EXIT_CORRUPT
+            } else if let Some(problem) = problem.as_ref().filter(|_| !trusted_key.is_empty()) {
+                eprintln!("CORRUPT: {}", problem);
+                EXIT_CORRUPT
+            } else if report.min_margin().is_some_and(|margin| margin < *min_margin) {
+                println!("DEGRADED: fewer than {} parity shards to spare in some chunks, re-encode soon", min_margin);
+                EXIT_DEGRADED
+            } else if report.hash == HashCheck::Unknown
+                || (report.hash == HashCheck::Unchecked && !matches!(report.signature, SignatureStatus::Valid(_)))
+            {
+                println!("UNVERIFIED: no hash or valid signature to check the data against");
+                EXIT_UNVERIFIED
+            } else {
+                // Without keys to trust, a signature is only a bonus
+                match problem {
+                    Some(problem) => println!("WARNING: {}", problem),
+                    None => println!("Video verified."),
+                }
+                0
+            };
+            std::process::exit(status);
+        }
+        Commands::Extract { input, file, output, read } => {
+            let output = match output {
+                Some(output) => output.clone(),
+                None => std::path::Path::new(file)
+                    .file_name()
+                    .with_context(|| format!("{} is not a file path, pass --output", file))?
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            println!("Mode: EXTRACT");
+            println!("Input: {}", input);
+            println!("File: {}", file);
             println!("Output: {}", output);
-            
-            let decoder = Decoder::new(input.clone(), output.clone());
-            decoder.run()?;
+
+            let decoder = Decoder::new(input.clone(), Destination::Path(output), read.options()?);
+            decoder.extract(file)?;
+        }
+        Commands::Scrub { input, json, csv, read } => {
+            println!("Mode: SCRUB");
+            println!("Input: {}", input);
+
+            let decoder = Decoder::new(input.clone(), Destination::Verify, read.options()?);
+            let report = decoder.scrub()?;
+            report.print();
+            if let Some(path) = json {
+                std::fs::write(path, serde_json::to_string_pretty(&report)?)
+                    .with_context(|| format!("Failed to write report {}", path))?;
+            }
+            if let Some(path) = csv {
+                report.write_csv(path)?;
+            }
+        }
+        Commands::Info { input, json } => {
+            let decoder = Decoder::new(input.clone(), Destination::Verify, DecodeOptions::default());
+            let info = decoder.info()?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                println!("Mode: INFO");
+                println!("Input: {}", input);
+                info.print();
+            }
+        }
+        Commands::Repair {
+            input,
+            output,
+            block_size,
+            data_shards,
+            parity_shards,
+            codec,
+            resolution,
+            fps,
+            bits_per_block,
+            color,
+            header_interval,
+            compress,
+            no_compress,
+            recipient,
+            recipients_file,
+            sign_key,
+            read,
+        } => {
+            println!("Mode: REPAIR");
+            println!("Input: {}", input);
+            println!("Output: {}", output);
+
+            let mut decode = read.options()?;
+            let header = Decoder::new(input.clone(), Destination::Verify, decode.clone()).header()?;
+            // Anything not asked for stays as it was
+            let old = header.params()?;
+            let params = EncodingParams {
+                geometry: resolution.unwrap_or(old.geometry),
+                fps: fps.unwrap_or(old.fps),
+                block_size: block_size.unwrap_or(old.block_size),
+                modulation: Modulation::new(
+                    bits_per_block.unwrap_or(old.modulation.bits_per_channel),
+                    color.unwrap_or(old.modulation.color),
+                )?,
+                data_shards: data_shards.unwrap_or(old.data_shards),
+                parity_shards: parity_shards.unwrap_or(old.parity_shards),
+            };
+            println!("Block Size: {}", params.block_size);
+            println!("RS Configuration: {} data / {} parity", params.data_shards, params.parity_shards);
+            println!("Codec: {}", codec);
+            println!("Resolution: {} @ {} fps", params.geometry, params.fps);
+
+            let mut recipients = recipient.clone();
+            for path in recipients_file {
+                recipients.extend(crypto::read_recipients_file(path)?);
+            }
+            // The new video is encrypted like the old one, under a fresh key.
+            // The same passphrase opens it, public keys have to be given again.
+            if header.encryption()?.is_some() && decode.passphrase.is_none() {
+                decode.passphrase = Some(crypto::prompt_passphrase(false)?);
+            }
+            if header.recipients()?.is_some() && recipients.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "This video is encrypted to public keys, which can't be read back from it. Pass --recipient for the new video."
+                ));
+            }
+            let passphrase = match header.encryption()? {
+                Some(_) if recipients.is_empty() => decode.passphrase.clone(),
+                _ => None,
+            };
+            let options = EncodeOptions {
+                codec: codec.clone(),
+                header_interval: match header_interval {
+                    Some(interval) => *interval,
+                    None => header.header_interval()?.unwrap_or(DEFAULT_HEADER_INTERVAL),
+                },
+                passphrase,
+                recipients,
+                sign_key: sign_key.as_deref().map(SignKey::read_file).transpose()?,
+                compression_level: match (compress, no_compress) {
+                    (_, true) => None,
+                    (Some(level), _) => Some(*level),
+                    (None, _) => header.compression()?.map(|c| c.level),
+                },
+                xattrs: false,
+            };
+
+            let repair = Repair::new(input.clone(), output.clone(), header, params, options, decode);
+            repair.run()?;
+        }
+        Commands::Keygen { output, sign: true } => {
+            let key = SignKey::generate()?;
+            key.write(output)?;
+            println!("Signing key written to {}", output);
+            println!("Verify key: {}", key.verify_key());
+        }
+        Commands::Keygen { output, sign: false } => {
+            let identity = Identity::generate()?;
+            identity.write(output)?;
+            println!("Secret key written to {}", output);
+            println!("Public key: {}", identity.recipient());
         }
     }
 
@@ -0,0 +1,287 @@
+use crate::archive::safe_path;
+use crate::utils::{read_field, to_hex, write_field};
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What is kept of a file besides its contents: the `EXT_METADATA` header
+/// extension of a single file, and part of every archive manifest entry.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Unix permission bits
+    pub mode: u32,
+    /// Modification time, in seconds since the epoch
+    pub mtime: i64,
+    /// Access time, likewise. Archives written before it was kept lack it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<i64>,
+    /// Extended attributes, only recorded on request
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty", with = "hex_values")]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl Metadata {
+    /// Reads the metadata of a file or directory, following symlinks.
+    pub fn read(path: &Path, xattrs: bool) -> Result<Self> {
+        let metadata = std::fs::metadata(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let default_mode = if metadata.is_dir() { 0o755 } else { 0o644 };
+        Ok(Self {
+            mode: mode(&metadata, default_mode),
+            mtime: unix_time(metadata.modified()?),
+            atime: metadata.accessed().ok().map(unix_time),
+            xattrs: if xattrs { read_xattrs(path)? } else { BTreeMap::new() },
+        })
+    }
+
+    /// Restores the metadata on a written file or directory, as far as
+    /// `restore` allows. Attributes the filesystem refuses are reported and skipped.
+    pub fn apply(&self, path: &Path, restore: &Restore) -> Result<()> {
+        // Before the mode, which may make the file read-only
+        for (name, value) in self.xattrs.iter().filter(|(name, _)| restore.xattrs && restore.allows(name)) {
+            if let Err(e) = write_xattr(path, name, value) {
+                eprintln!("Can't restore attribute {} of {}: {}", name, path.display(), e);
+            }
+        }
+        let mode = match restore.setid {
+            true => self.mode,
+            false => self.mode & !SETID_BITS,
+        };
+        set_mode(path, mode & 0o7777)?;
+        let mtime = FileTime::from_unix_time(self.mtime, 0);
+        match self.atime {
+            Some(atime) => filetime::set_file_times(path, FileTime::from_unix_time(atime, 0), mtime),
+            None => filetime::set_file_mtime(path, mtime),
+        }
+        .with_context(|| format!("Failed to set times of {}", path.display()))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        bytes.write_u32::<BigEndian>(self.mode)?;
+        bytes.write_i64::<BigEndian>(self.mtime)?;
+        bytes.write_i64::<BigEndian>(self.atime.unwrap_or(self.mtime))?;
+        bytes.write_u16::<BigEndian>(self.xattrs.len().try_into().context("Too many extended attributes")?)?;
+        for (name, value) in &self.xattrs {
+            write_field(&mut bytes, name.as_bytes())?;
+            write_field(&mut bytes, value)?;
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let mode = bytes.read_u32::<BigEndian>()?;
+        let mtime = bytes.read_i64::<BigEndian>()?;
+        let atime = bytes.read_i64::<BigEndian>()?;
+        let mut xattrs = BTreeMap::new();
+        for _ in 0..bytes.read_u16::<BigEndian>()? {
+            let name = String::from_utf8(read_field(&mut bytes)?.to_vec()).context("Invalid UTF-8 in attribute name")?;
+            xattrs.insert(name, read_field(&mut bytes)?.to_vec());
+        }
+        Ok(Self { mode, mtime, atime: Some(atime), xattrs })
+    }
+}
+
+/// Setuid, setgid and sticky
+const SETID_BITS: u32 = 0o7000;
+
+/// What of the recorded metadata a decode may put back. It comes from the
+/// video, which may be crafted, so the risky parts are opt-in.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Restore {
+    /// Extended attributes, of the `user.` namespace only. `security.` and
+    /// `trusted.` ones could grant capabilities or fool other software.
+    pub xattrs: bool,
+    /// The setuid, setgid and sticky bits
+    pub setid: bool,
+}
+
+impl Restore {
+    fn allows(&self, name: &str) -> bool {
+        name.starts_with("user.")
+    }
+}
+
+/// The name an input is stored under: its last path component, never the
+/// path it was found at.
+pub fn file_name(path: &str) -> Result<String> {
+    std::fs::canonicalize(path)
+        .with_context(|| format!("Failed to open input {}", path))?
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(str::to_string)
+        .with_context(|| format!("Input {} has no usable name", path))
+}
+
+/// Where `decode` writes a single file by default: the stored name in the
+/// current directory. Only the last component of the name counts, so older
+/// videos that stored a full path, or a crafted header, can't write elsewhere.
+pub fn stored_path(name: &str) -> Result<PathBuf> {
+    let base = Path::new(name)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("Stored file name {:?} is unusable, pass --output", name))?;
+    safe_path(Path::new("."), base)
+}
+
+pub fn unix_time(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &std::fs::Metadata, _default: u32) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &std::fs::Metadata, default: u32) -> u32 {
+    default
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set permissions of {}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut xattrs = BTreeMap::new();
+    let names = xattr::list(path).with_context(|| format!("Failed to list attributes of {}", path.display()))?;
+    for name in names {
+        let Some(name) = name.to_str().map(str::to_string) else {
+            eprintln!("Skipping attribute of {}: name is not valid UTF-8", path.display());
+            continue;
+        };
+        if let Some(value) = xattr::get(path, &name)? {
+            xattrs.insert(name, value);
+        }
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(unix))]
+fn read_xattrs(_path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    Ok(BTreeMap::new())
+}
+
+#[cfg(unix)]
+fn write_xattr(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+    xattr::set(path, name, value)
+}
+
+#[cfg(not(unix))]
+fn write_xattr(_path: &Path, _name: &str, _value: &[u8]) -> std::io::Result<()> {
+    Err(std::io::Error::other("not supported on this platform"))
+}
+
+/// Attribute values as hex strings in the manifest JSON
+mod hex_values {
+    use super::to_hex;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(map: &BTreeMap<String, Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(map.iter().map(|(name, value)| (name, to_hex(value))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, Vec<u8>>, D::Error> {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(name, hex)| {
+                let value = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| hex.get(i..i + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok()))
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(|| D::Error::custom(format!("Invalid hex value of attribute {}", name)))?;
+                Ok((name, value))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let path = std::env::temp_dir().join(format!("b2v-metadata-{}", std::process::id()));
+        std::fs::write(&path, b"data").unwrap();
+        let mut metadata = Metadata {
+            mode: 0o640,
+            mtime: 1_577_934_245,
+            atime: Some(1_600_000_000),
+            xattrs: BTreeMap::new(),
+        };
+        metadata.xattrs.insert("user.b2v.test".to_string(), vec![0, 1, 0xff]);
+        assert_eq!(Metadata::from_bytes(&metadata.to_bytes().unwrap()).unwrap(), metadata);
+        let json = serde_json::to_string(&metadata).unwrap();
+        assert!(json.contains(r#""user.b2v.test":"0001ff""#));
+        assert_eq!(serde_json::from_str::<Metadata>(&json).unwrap(), metadata);
+
+        // Not every filesystem takes user attributes, tmpfs often doesn't
+        metadata.apply(&path, &Restore { xattrs: true, setid: false }).unwrap();
+        let restored = Metadata::read(&path, cfg!(unix)).unwrap();
+        assert_eq!((restored.mtime, restored.atime), (metadata.mtime, metadata.atime));
+        #[cfg(unix)]
+        assert_eq!(restored.mode, 0o640);
+        if !restored.xattrs.is_empty() {
+            assert_eq!(restored.xattrs, metadata.xattrs);
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_restore_is_opt_in() {
+        let path = std::env::temp_dir().join(format!("b2v-restore-{}", std::process::id()));
+        std::fs::write(&path, b"data").unwrap();
+        let mut metadata = Metadata { mode: 0o6755, mtime: 1_577_934_245, atime: None, xattrs: BTreeMap::new() };
+        metadata.xattrs.insert("user.b2v.test".to_string(), vec![1]);
+        metadata.xattrs.insert("security.capability".to_string(), vec![1, 0, 0, 2]);
+
+        // What a plain decode does
+        metadata.apply(&path, &Restore::default()).unwrap();
+        let restored = Metadata::read(&path, cfg!(unix)).unwrap();
+        #[cfg(unix)]
+        assert_eq!(restored.mode, 0o755);
+        assert!(!restored.xattrs.contains_key("user.b2v.test"));
+        assert!(!restored.xattrs.contains_key("security.capability"));
+
+        // Even when asked for, only user attributes come back
+        let restore = Restore { xattrs: true, setid: true };
+        assert!(restore.allows("user.b2v.test"));
+        assert!(!restore.allows("security.capability") && !restore.allows("trusted.overlay.opaque"));
+        metadata.xattrs.remove("user.b2v.test");
+        metadata.apply(&path, &restore).unwrap();
+        let restored = Metadata::read(&path, cfg!(unix)).unwrap();
+        #[cfg(unix)]
+        assert_eq!(restored.mode, 0o6755);
+        assert!(!restored.xattrs.contains_key("security.capability"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_stored_path() {
+        assert_eq!(stored_path("a.bin").unwrap(), Path::new("./a.bin"));
+        // Full paths from older videos, and attempts to climb out
+        assert_eq!(stored_path("/home/user/backup.iso").unwrap(), Path::new("./backup.iso"));
+        assert_eq!(stored_path("../../etc/passwd").unwrap(), Path::new("./passwd"));
+        assert!(stored_path("..").is_err());
+        assert!(stored_path("/").is_err());
+        assert!(stored_path("").is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::Result;
+
+pub const MAX_BITS_PER_BLOCK: u8 = 4;
+
+/// How data bits map onto block colours. Each channel sample carries
+/// `bits_per_channel` bits as one of 2^bits evenly spaced levels, from black to
+/// white. In gray mode all three channels carry the same symbol; in colour mode
+/// R, G and B are modulated independently, tripling the bits per block.
+/// One bit, gray, is the classic black/white mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modulation {
+    pub bits_per_channel: u8,
+    pub color: bool,
+}
+
+impl Modulation {
+    /// Used for the bootstrap frames and frame header strips, whatever the data frames use.
+    pub const BINARY: Self = Self { bits_per_channel: 1, color: false };
+
+    pub fn new(bits_per_channel: u8, color: bool) -> Result<Self> {
+        if !(1..=MAX_BITS_PER_BLOCK).contains(&bits_per_channel) {
+            return Err(anyhow::anyhow!(
+                "Bits per block must be between 1 and {}, got {}",
+                MAX_BITS_PER_BLOCK,
+                bits_per_channel
+            ));
+        }
+        Ok(Self { bits_per_channel, color })
+    }
+
+    /// Independent symbols per block: one per RGB channel in colour mode.
+    pub fn channels(&self) -> usize {
+        if self.color { 3 } else { 1 }
+    }
+
+    pub fn bits_per_block(&self) -> usize {
+        self.bits_per_channel as usize * self.channels()
+    }
+
+    pub fn levels(&self) -> usize {
+        1 << self.bits_per_channel
+    }
+
+    /// Pixel value for a symbol. Symbols are Gray-coded onto the levels, so
+    /// mistaking a block for a neighbouring level only costs a single bit.
+    pub fn symbol_to_value(&self, symbol: u8) -> u8 {
+        let level = gray_decode(symbol) as usize;
+        (level * 255 / (self.levels() - 1)) as u8
+    }
+}
+
+/// Level index carrying a symbol: the inverse of the `n ^ (n >> 1)` Gray
+/// code, which gives the symbol carried by level `n`.
+pub fn gray_decode(gray: u8) -> u8 {
+    let mut value = gray;
+    let mut shift = gray >> 1;
+    while shift != 0 {
+        value ^= shift;
+        shift >>= 1;
+    }
+    value
+}
+
+/// Reads the `bits`-wide symbol at `index` from a MSB-first bit stream.
+/// Anything past the end of `data` reads as zero (padding).
+pub fn read_symbol(data: &[u8], index: usize, bits: u8) -> u8 {
+    let start = index * bits as usize;
+    (0..bits as usize).fold(0u8, |symbol, i| {
+        let bit_idx = start + i;
+        let bit = data
+            .get(bit_idx / 8)
+            .map_or(0, |byte| (byte >> (7 - bit_idx % 8)) & 1);
+        (symbol << 1) | bit
+    })
+}
+
+/// Packs `bits`-wide symbols MSB first into `len` bytes, dropping whatever doesn't fit.
+pub fn pack_symbols(symbols: impl IntoIterator<Item = u8>, bits: u8, len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    for (index, symbol) in symbols.into_iter().enumerate() {
+        for i in 0..bits as usize {
+            let bit_idx = index * bits as usize + i;
+            let byte_idx = bit_idx / 8;
+            if byte_idx >= len {
+                return bytes;
+            }
+            if (symbol >> (bits as usize - 1 - i)) & 1 == 1 {
+                bytes[byte_idx] |= 1 << (7 - bit_idx % 8);
+            }
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::Levels;
+
+    #[test]
+    fn test_levels_round_trip_with_gray_coding() {
+        for bits in 1..=MAX_BITS_PER_BLOCK {
+            let modulation = Modulation::new(bits, false).unwrap();
+            let levels = Levels::nominal(modulation);
+            let step = 255 / (modulation.levels() - 1);
+            for symbol in 0..modulation.levels() as u8 {
+                let value = modulation.symbol_to_value(symbol);
+                assert_eq!(levels.classify(value as f64).0, symbol);
+
+                // Drifting a bit less than half a step still lands on the same level
+                let drift = (step / 2).saturating_sub(1) as u8;
+                assert_eq!(levels.classify(value.saturating_add(drift) as f64).0, symbol);
+                assert_eq!(levels.classify(value.saturating_sub(drift) as f64).0, symbol);
+            }
+        }
+        assert!(Modulation::new(0, false).is_err());
+        assert!(Modulation::new(MAX_BITS_PER_BLOCK + 1, true).is_err());
+        assert_eq!(Modulation::new(2, true).unwrap().bits_per_block(), 6);
+    }
+
+    #[test]
+    fn test_neighbouring_levels_differ_by_one_bit() {
+        let modulation = Modulation::new(3, false).unwrap();
+        let mut by_value: Vec<u8> = (0..8).collect();
+        by_value.sort_by_key(|&s| modulation.symbol_to_value(s));
+        for pair in by_value.windows(2) {
+            assert_eq!((pair[0] ^ pair[1]).count_ones(), 1);
+        }
+    }
+
+    #[test]
+    fn test_symbol_packing() {
+        let data = [0b1011_0010, 0b0111_0000];
+        let symbols: Vec<u8> = (0..4).map(|i| read_symbol(&data, i, 3)).collect();
+        assert_eq!(symbols, vec![0b101, 0b100, 0b100, 0b111]);
+        assert_eq!(pack_symbols(symbols, 3, 2), vec![0b1011_0010, 0b0111_0000]);
+        assert_eq!(read_symbol(&data, 10, 3), 0); // Padding
+    }
+
+}
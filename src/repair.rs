@@ -0,0 +1,149 @@
+use crate::decoder::{ChunkStatus, DecodeOptions, Decoder, Destination, HashCheck};
+use crate::encoder::{EncodeOptions, Encoder, StreamInput};
+use crate::utils::{EncodingParams, FileHeader};
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::sync::mpsc::{sync_channel, Receiver};
+
+/// Decoded pieces in flight between the decoder and the encoder. Enough to
+/// keep both busy, few enough that the data never piles up in memory.
+const STREAM_DEPTH: usize = 16;
+
+/// Rewrites a damaged video as a fresh one. Chunks are decoded, rebuilt
+/// with Reed-Solomon where needed and passed straight on to an encoder, so
+/// the data is never written to disk in between.
+pub struct Repair {
+    input_path: String,
+    output_path: String,
+    /// Of the damaged video
+    header: FileHeader,
+    params: EncodingParams,
+    encode: EncodeOptions,
+    decode: DecodeOptions,
+}
+
+impl Repair {
+    pub fn new(
+        input_path: String,
+        output_path: String,
+        header: FileHeader,
+        params: EncodingParams,
+        encode: EncodeOptions,
+        decode: DecodeOptions,
+    ) -> Self {
+        Self { input_path, output_path, header, params, encode, decode }
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let result = self.transcode();
+        if result.is_err() {
+            // Whatever was written is incomplete, or holds damaged data
+            let _ = std::fs::remove_file(&self.output_path);
+        }
+        result
+    }
+
+    fn transcode(&self) -> Result<()> {
+        let (sender, receiver) = sync_channel(STREAM_DEPTH);
+        let decoder = Decoder::new(self.input_path.clone(), Destination::Stream(sender), self.decode.clone());
+        let encoder = Encoder::new(Vec::new(), self.output_path.clone(), self.params, self.encode.clone());
+
+        let (decoded, encoded) = std::thread::scope(|scope| {
+            let decoding = scope.spawn(move || decoder.run());
+            let encoded = StreamInput::new(&self.header, Box::new(StreamReader::new(receiver, self.header.file_size)))
+                .and_then(|stream| encoder.run_stream(stream));
+            let decoded = decoding.join().unwrap_or_else(|_| Err(anyhow::anyhow!("Decoder panicked")));
+            (decoded, encoded)
+        });
+        // The decoder stopping is what starves the encoder, so its error comes first
+        let report = decoded.context("Decoding the damaged video failed")?;
+        let new_hash = encoded.context("Encoding the new video failed")?;
+
+        let lost = report.count(ChunkStatus::Unrecoverable);
+        if lost > 0 {
+            return Err(anyhow::anyhow!("{} chunk(s) could not be recovered", lost));
+        }
+        match &report.hash {
+            HashCheck::Mismatch { expected, calculated } => {
+                return Err(anyhow::anyhow!(
+                    "Hash mismatch! Expected {}, got {}. The damaged video can't be repaired.",
+                    expected,
+                    calculated
+                ))
+            }
+            HashCheck::Verified => println!("Repaired data matches the original hash: {}", new_hash),
+            _ => println!("WARNING: No hash to check the repaired data against, it is only as good as the shard CRCs"),
+        }
+        println!(
+            "Chunks: {} clean, {} repaired",
+            report.count(ChunkStatus::Clean),
+            report.count(ChunkStatus::Repaired)
+        );
+        println!("New video written to {}", self.output_path);
+        Ok(())
+    }
+}
+
+/// The decoded data as the encoder reads it. Ends in an error, not a clean
+/// end of file, if the decoder stops before handing over all of it.
+struct StreamReader {
+    receiver: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    position: usize,
+    remaining: u64,
+}
+
+impl StreamReader {
+    fn new(receiver: Receiver<Vec<u8>>, size: u64) -> Self {
+        Self { receiver, buffer: Vec::new(), position: 0, remaining: size }
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(piece) => {
+                    self.buffer = piece;
+                    self.position = 0;
+                }
+                Err(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        format!("Decoding stopped {} bytes short", self.remaining),
+                    ))
+                }
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        self.remaining = self.remaining.saturating_sub(n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_reader_catches_early_end() {
+        let (sender, receiver) = sync_channel(4);
+        sender.send(b"hello ".to_vec()).unwrap();
+        sender.send(b"world".to_vec()).unwrap();
+        drop(sender);
+        let mut data = String::new();
+        StreamReader::new(receiver, 11).read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello world");
+
+        let (sender, receiver) = sync_channel(4);
+        sender.send(b"hello".to_vec()).unwrap();
+        drop(sender);
+        let error = StreamReader::new(receiver, 11).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
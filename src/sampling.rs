@@ -0,0 +1,94 @@
+use crate::calibration::Levels;
+use crate::modulation::MAX_BITS_PER_BLOCK;
+use clap::ValueEnum;
+
+/// How the decoder turns the pixels of a block into a symbol. Lossy codecs
+/// ring around block edges, so reading the whole interior of a block is far
+/// less fragile than trusting a single pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Sampling {
+    /// The single pixel at the centre of the block
+    Centre,
+    /// Mean of the block interior
+    #[default]
+    Mean,
+    /// Median of the block interior, ignoring outliers
+    Median,
+    /// Most common symbol among the interior pixels
+    Majority,
+}
+
+impl Sampling {
+    /// Pixels left out along each edge of a block, where neighbouring blocks
+    /// bleed in. Small blocks are read whole.
+    pub fn inset(&self, block_size: usize) -> usize {
+        match self {
+            Self::Centre => 0,
+            _ => block_size / 4,
+        }
+    }
+
+    /// Reads one channel of a block from its sampled `values` (just the
+    /// centre pixel for `Centre`) against that channel's `levels`. Returns the
+    /// symbol and a confidence from 0 (a coin toss) to 255 (certain).
+    pub fn read(&self, values: &mut [u8], levels: &Levels) -> (u8, u8) {
+        match self {
+            Self::Centre | Self::Mean | Self::Median => levels.classify(self.value(values)),
+            Self::Majority => {
+                // Confidence is the share of pixels agreeing with the winner
+                let mut votes = [0usize; 1 << MAX_BITS_PER_BLOCK];
+                for &value in values.iter() {
+                    votes[levels.classify(value as f64).0 as usize] += 1;
+                }
+                let (symbol, count) = votes
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|&(_, count)| *count)
+                    .unwrap_or((0, &0));
+                (symbol as u8, (count * 255 / values.len().max(1)) as u8)
+            }
+        }
+    }
+
+    /// The single value `read` classifies a channel of a block by. `Majority`
+    /// classifies every pixel instead, so it gets the median.
+    pub fn value(&self, values: &mut [u8]) -> f64 {
+        match self {
+            Self::Centre | Self::Mean => values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64,
+            Self::Median | Self::Majority => {
+                values.sort_unstable();
+                values[values.len() / 2] as f64
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modulation::Modulation;
+
+    #[test]
+    fn test_interior_sampling_survives_ringing() {
+        // A white block with a couple of dark outliers from compression ringing
+        let block = [255u8, 250, 12, 248, 255, 40, 252, 255, 251];
+        let levels = Levels::nominal(Modulation::BINARY);
+
+        let (symbol, confidence) = Sampling::Mean.read(&mut block.clone(), &levels);
+        assert_eq!(symbol, 1);
+        assert!(confidence < 255);
+
+        assert_eq!(Sampling::Median.read(&mut block.clone(), &levels).0, 1);
+        assert_eq!(Sampling::Majority.read(&mut block.clone(), &levels), (1, 198)); // 7 of 9 agree
+
+        // A single pixel read is fooled when the outlier lands on it
+        assert_eq!(Sampling::Centre.read(&mut [block[2]], &levels).0, 0);
+    }
+
+    #[test]
+    fn test_inset() {
+        assert_eq!(Sampling::Mean.inset(8), 2);
+        assert_eq!(Sampling::Majority.inset(2), 0);
+        assert_eq!(Sampling::Centre.inset(8), 0);
+    }
+}
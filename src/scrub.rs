@@ -0,0 +1,366 @@
+use crate::calibration::Levels;
+use crate::layout::Layout;
+use crate::modulation::{read_symbol, Modulation};
+use crate::utils::EncodingParams;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::Write;
+
+/// The body of a frame is split into up to this many regions across and down.
+pub const REGION_GRID: usize = 8;
+/// Symbols read less than this far inside their threshold count as marginal.
+pub const MARGINAL: f64 = 16.0;
+
+// Margins from -128 to 128, in bins of 8
+const HISTOGRAM_START: i32 = -128;
+const HISTOGRAM_BIN: i32 = 8;
+const HISTOGRAM_BINS: usize = 32;
+
+/// How the body of a data frame fared against its own CRC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameRead {
+    Intact,
+    /// Matches its CRC, but some bit was read with less than the minimum confidence
+    Weak,
+    Damaged,
+}
+
+/// One data frame's body as the decoder read it: every symbol, the value it
+/// was read from and the levels it was read against, one set per channel.
+pub struct BodySample {
+    pub frame_number: u32,
+    pub chunk: u64,
+    pub shard: usize,
+    pub read: FrameRead,
+    pub symbols: Vec<u8>,
+    pub values: Vec<f32>,
+    pub levels: Vec<Levels>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameStats {
+    pub frame_number: u32,
+    pub chunk: u64,
+    pub shard: usize,
+    pub read: FrameRead,
+    pub bits: u64,
+    pub bit_errors: u64,
+    pub bit_error_rate: f64,
+    /// Distance from the threshold of the worst symbol, negative if misread
+    pub min_margin: f64,
+    pub mean_margin: f64,
+    /// Symbols read less than `MARGINAL` inside their threshold, or misread
+    pub marginal_symbols: u64,
+}
+
+/// Symbols by how far their value lay inside the threshold of the level
+/// they should have read as. Negative margins are bit errors.
+#[derive(Debug, Clone, Serialize)]
+pub struct MarginHistogram {
+    /// Lower edge of the first bin. Values beyond either end go in the end bins.
+    pub start: i32,
+    pub bin_width: i32,
+    pub counts: Vec<u64>,
+}
+
+impl MarginHistogram {
+    fn add(&mut self, margin: f64) {
+        let bin = ((margin.floor() as i32 - self.start) / self.bin_width).clamp(0, self.counts.len() as i32 - 1);
+        self.counts[bin as usize] += 1;
+    }
+}
+
+/// One region of the body, in encoded pixel coordinates, over every frame measured.
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionStats {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub bits: u64,
+    pub bit_errors: u64,
+    pub bit_error_rate: f64,
+    pub mean_margin: f64,
+    #[serde(skip)]
+    margin_sum: f64,
+    #[serde(skip)]
+    symbols: u64,
+}
+
+/// What `scrub` found: how far each data frame was from being misread,
+/// measured against the shards its chunk was rebuilt to.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScrubReport {
+    pub block_size: String,
+    pub bits_per_block: u8,
+    pub color: bool,
+    pub bits: u64,
+    pub bit_errors: u64,
+    pub bit_error_rate: f64,
+    pub frames: Vec<FrameStats>,
+    /// Data frames that couldn't be placed, or whose chunk was lost, so
+    /// there was nothing to measure them against
+    pub unmeasured_frames: u64,
+    pub histogram: MarginHistogram,
+    /// Row by row from the top left
+    pub regions: Vec<RegionStats>,
+    #[serde(skip)]
+    cell_regions: Vec<usize>,
+}
+
+impl ScrubReport {
+    pub fn new(params: &EncodingParams, body: &Layout) -> Self {
+        // Fewer regions on a side than cells, so none is empty
+        let (across, down) = (REGION_GRID.min(body.cols()), REGION_GRID.min(body.cell_rows()));
+        let cell_regions: Vec<usize> = (0..body.cells())
+            .map(|cell| {
+                let (col, row) = (cell % body.cols(), cell / body.cols());
+                (row * down / body.cell_rows()) * across + col * across / body.cols()
+            })
+            .collect();
+        let mut bounds = vec![(usize::MAX, usize::MAX, 0, 0); across * down];
+        for (cell, &region) in cell_regions.iter().enumerate() {
+            let (xs, ys) = body.cell(cell);
+            let (x0, y0, x1, y1) = &mut bounds[region];
+            (*x0, *y0, *x1, *y1) = ((*x0).min(xs.start), (*y0).min(ys.start), (*x1).max(xs.end), (*y1).max(ys.end));
+        }
+        let regions = bounds
+            .into_iter()
+            .map(|(x0, y0, x1, y1)| RegionStats {
+                x: x0,
+                y: y0,
+                width: x1 - x0,
+                height: y1 - y0,
+                bits: 0,
+                bit_errors: 0,
+                bit_error_rate: 0.0,
+                mean_margin: 0.0,
+                margin_sum: 0.0,
+                symbols: 0,
+            })
+            .collect();
+
+        Self {
+            block_size: params.block_size.to_string(),
+            bits_per_block: params.modulation.bits_per_channel,
+            color: params.modulation.color,
+            bits: 0,
+            bit_errors: 0,
+            bit_error_rate: 0.0,
+            frames: Vec::new(),
+            unmeasured_frames: 0,
+            histogram: MarginHistogram {
+                start: HISTOGRAM_START,
+                bin_width: HISTOGRAM_BIN,
+                counts: vec![0; HISTOGRAM_BINS],
+            },
+            regions,
+            cell_regions,
+        }
+    }
+
+    /// Measures a frame against the shard it should have held.
+    pub fn add(&mut self, sample: BodySample, shard: &[u8], modulation: Modulation) {
+        let channels = modulation.channels();
+        let bits = modulation.bits_per_channel;
+        let mut stats = FrameStats {
+            frame_number: sample.frame_number,
+            chunk: sample.chunk,
+            shard: sample.shard,
+            read: sample.read,
+            bits: 0,
+            bit_errors: 0,
+            bit_error_rate: 0.0,
+            min_margin: f64::INFINITY,
+            mean_margin: 0.0,
+            marginal_symbols: 0,
+        };
+        let mut margin_sum = 0.0;
+        for (index, (&symbol, &value)) in sample.symbols.iter().zip(&sample.values).enumerate() {
+            // Past the shard, the encoder draws zeros
+            let expected = read_symbol(shard, index, bits);
+            let errors = (symbol ^ expected).count_ones() as u64;
+            let margin = sample.levels[index % channels].margin(value as f64, expected);
+
+            stats.bits += bits as u64;
+            stats.bit_errors += errors;
+            stats.min_margin = stats.min_margin.min(margin);
+            stats.marginal_symbols += (margin < MARGINAL) as u64;
+            margin_sum += margin;
+            self.histogram.add(margin);
+            if let Some(region) = self.cell_regions.get(index / channels).and_then(|&r| self.regions.get_mut(r)) {
+                region.bits += bits as u64;
+                region.bit_errors += errors;
+                region.margin_sum += margin;
+                region.symbols += 1;
+            }
+        }
+        let symbols = sample.symbols.len().max(1) as f64;
+        stats.mean_margin = margin_sum / symbols;
+        stats.bit_error_rate = rate(stats.bit_errors, stats.bits);
+        self.bits += stats.bits;
+        self.bit_errors += stats.bit_errors;
+        self.frames.push(stats);
+    }
+
+    /// Works out the totals once every frame is in.
+    pub fn finish(&mut self) {
+        self.bit_error_rate = rate(self.bit_errors, self.bits);
+        for region in &mut self.regions {
+            region.bit_error_rate = rate(region.bit_errors, region.bits);
+            region.mean_margin = region.margin_sum / region.symbols.max(1) as f64;
+        }
+    }
+
+    /// The regions most likely to fail first: most bit errors, then least margin.
+    pub fn worst_regions(&self, count: usize) -> Vec<&RegionStats> {
+        let mut regions: Vec<&RegionStats> = self.regions.iter().filter(|r| r.symbols > 0).collect();
+        regions.sort_by(|a, b| {
+            b.bit_error_rate
+                .total_cmp(&a.bit_error_rate)
+                .then(a.mean_margin.total_cmp(&b.mean_margin))
+        });
+        regions.truncate(count);
+        regions
+    }
+
+    /// The frames most likely to fail first, likewise.
+    pub fn worst_frames(&self, count: usize) -> Vec<&FrameStats> {
+        let mut frames: Vec<&FrameStats> = self.frames.iter().collect();
+        frames.sort_by(|a, b| {
+            b.bit_error_rate
+                .total_cmp(&a.bit_error_rate)
+                .then(a.min_margin.total_cmp(&b.min_margin))
+        });
+        frames.truncate(count);
+        frames
+    }
+
+    pub fn print(&self) {
+        println!("Block Size: {}, {} bit(s) per {}", self.block_size, self.bits_per_block, if self.color { "channel" } else { "block" });
+        println!("Frames measured: {}, unmeasured: {}", self.frames.len(), self.unmeasured_frames);
+        println!("Bit errors: {} of {} bits (BER {:.2e})", self.bit_errors, self.bits, self.bit_error_rate);
+        let damaged = self.frames.iter().filter(|f| f.read != FrameRead::Intact).count();
+        println!("Frames needing parity: {}", damaged);
+
+        println!("Margin histogram (distance inside the threshold):");
+        let total: u64 = self.histogram.counts.iter().sum();
+        for (bin, &count) in self.histogram.counts.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let start = self.histogram.start + bin as i32 * self.histogram.bin_width;
+            let share = count as f64 / total.max(1) as f64;
+            println!(
+                "  {:>5} to {:>4}: {:>10} {:>6.2}% {}",
+                start,
+                start + self.histogram.bin_width,
+                count,
+                share * 100.0,
+                "#".repeat((share * 50.0).ceil() as usize)
+            );
+        }
+
+        println!("Worst regions:");
+        for region in self.worst_regions(5) {
+            println!(
+                "  {}x{} at ({}, {}): BER {:.2e}, mean margin {:.1}",
+                region.width, region.height, region.x, region.y, region.bit_error_rate, region.mean_margin
+            );
+        }
+        println!("Worst frames:");
+        for frame in self.worst_frames(5) {
+            println!(
+                "  Frame {} (chunk {}, shard {}): BER {:.2e}, min margin {:.1}, {}",
+                frame.frame_number,
+                frame.chunk,
+                frame.shard,
+                frame.bit_error_rate,
+                frame.min_margin,
+                serde_json::to_value(frame.read).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default()
+            );
+        }
+    }
+
+    /// One row per frame measured.
+    pub fn write_csv(&self, path: &str) -> Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path).with_context(|| format!("Failed to create {}", path))?);
+        writeln!(file, "frame_number,chunk,shard,read,bits,bit_errors,bit_error_rate,min_margin,mean_margin,marginal_symbols")?;
+        for frame in &self.frames {
+            let read = serde_json::to_value(frame.read)?;
+            writeln!(
+                file,
+                "{},{},{},{},{},{},{},{},{:.3},{}",
+                frame.frame_number,
+                frame.chunk,
+                frame.shard,
+                read.as_str().unwrap_or_default(),
+                frame.bits,
+                frame.bit_errors,
+                frame.bit_error_rate,
+                frame.min_margin,
+                frame.mean_margin,
+                frame.marginal_symbols
+            )?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+fn rate(errors: u64, bits: u64) -> f64 {
+    if bits == 0 { 0.0 } else { errors as f64 / bits as f64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::BlockSize;
+    use crate::utils::{body_layout, Geometry, DEFAULT_FPS};
+
+    #[test]
+    fn test_scrub_measures_against_shard() {
+        let params = EncodingParams {
+            geometry: Geometry::new(480, 270),
+            fps: DEFAULT_FPS,
+            block_size: BlockSize::square(8),
+            modulation: Modulation::BINARY,
+            data_shards: 4,
+            parity_shards: 2,
+        };
+        let body = body_layout(params.geometry, params.block_size);
+        let mut report = ScrubReport::new(&params, &body);
+        assert_eq!(report.regions.len(), REGION_GRID * REGION_GRID);
+
+        // A shard of alternating bits, read back with the first symbol flipped
+        let shard = vec![0b0101_0101; body.capacity(1)];
+        let levels = Levels::nominal(Modulation::BINARY);
+        let expected: Vec<u8> = (0..body.cells()).map(|i| read_symbol(&shard, i, 1)).collect();
+        let mut symbols = expected.clone();
+        symbols[0] ^= 1;
+        let mut values: Vec<f32> = expected.iter().map(|&s| if s == 1 { 200.0 } else { 20.0 }).collect();
+        values[0] = 160.0;
+        let sample = BodySample {
+            frame_number: 1,
+            chunk: 0,
+            shard: 0,
+            read: FrameRead::Damaged,
+            symbols,
+            values,
+            levels: vec![levels],
+        };
+        report.add(sample, &shard, params.modulation);
+        report.finish();
+
+        let frame = &report.frames[0];
+        assert_eq!((frame.bits, frame.bit_errors), (body.cells() as u64, 1));
+        assert_eq!(frame.min_margin, -32.5);
+        assert_eq!(frame.marginal_symbols, 1);
+        // 160 should have been 0: bin -40 to -32
+        assert_eq!(report.histogram.counts[(-40 - HISTOGRAM_START) as usize / 8], 1);
+        let worst = report.worst_regions(1)[0];
+        assert_eq!((worst.x, worst.y, worst.bit_errors), (0, body.cell(0).1.start, 1));
+        assert!(report.regions.iter().map(|r| r.bits).sum::<u64>() == report.bits);
+    }
+}
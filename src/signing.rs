@@ -0,0 +1,278 @@
+use crate::utils::{from_hex, to_hex, FileHeader, FileTrailer};
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, WriteBytesExt};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+// How keys are written out: a prefix, then the 32 key bytes in hex
+const SIGNING_KEY_PREFIX: &str = "B2V-SIGNING-KEY-";
+const VERIFY_KEY_PREFIX: &str = "B2V-VERIFY-KEY-";
+
+/// Domain separation of the signed message, and its layout version.
+const SIGNATURE_CONTEXT: &[u8] = b"b2v signature v1\0";
+
+/// Public half of a signing key, as passed to `verify --trusted-key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyKey(VerifyingKey);
+
+impl fmt::Display for VerifyKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", VERIFY_KEY_PREFIX, to_hex(self.0.as_bytes()))
+    }
+}
+
+impl FromStr for VerifyKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s
+            .trim()
+            .strip_prefix(VERIFY_KEY_PREFIX)
+            .with_context(|| format!("Verify keys start with {}", VERIFY_KEY_PREFIX))?;
+        let key = VerifyingKey::from_bytes(&from_hex(hex)?).context("Invalid verify key")?;
+        Ok(Self(key))
+    }
+}
+
+/// Ed25519 key the encoder signs videos with.
+#[derive(Clone)]
+pub struct SignKey(SigningKey);
+
+impl fmt::Debug for SignKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SignKey({})", self.verify_key())
+    }
+}
+
+impl SignKey {
+    pub fn generate() -> Result<Self> {
+        let mut secret = [0u8; 32];
+        getrandom::getrandom(&mut secret).map_err(|e| anyhow::anyhow!("Failed to generate key: {}", e))?;
+        Ok(Self(SigningKey::from_bytes(&secret)))
+    }
+
+    pub fn verify_key(&self) -> VerifyKey {
+        VerifyKey(self.0.verifying_key())
+    }
+
+    /// Writes the key file `keygen --sign` creates: the verify key as a
+    /// comment, then the signing key. Readable by the owner only where that applies.
+    pub fn write(&self, path: &str) -> Result<()> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("Failed to create signing key file {}", path))?;
+        writeln!(file, "# verify key: {}", self.verify_key())?;
+        writeln!(file, "{}{}", SIGNING_KEY_PREFIX, to_hex(self.0.as_bytes()))?;
+        Ok(())
+    }
+
+    /// Reads the signing key from a key file, skipping comments.
+    pub fn read_file(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read signing key file {}", path))?;
+        let line = contents
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .with_context(|| format!("No signing key found in {}", path))?;
+        let hex = line
+            .strip_prefix(SIGNING_KEY_PREFIX)
+            .with_context(|| format!("Not a signing key in {}", path))?;
+        Ok(Self(SigningKey::from_bytes(&from_hex(hex).context("Invalid signing key")?)))
+    }
+
+    /// Signs the finished video: records the Merkle root and the signature in the trailer.
+    pub fn sign(&self, header: &FileHeader, trailer: &mut FileTrailer, merkle_root: String) -> Result<()> {
+        trailer.merkle_root = Some(merkle_root);
+        let signature = self.0.sign(&signed_message(header, trailer)?);
+        trailer.signature = Some(to_hex(&signature.to_bytes()));
+        trailer.signer = Some(self.verify_key().to_string());
+        Ok(())
+    }
+}
+
+/// What the trailer's signature says about a video.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Unsigned,
+    /// The signature doesn't match the header and trailer, or can't be read
+    Invalid,
+    Valid(VerifyKey),
+}
+
+impl SignatureStatus {
+    /// Checks the trailer's signature over the header and the trailer's
+    /// hash, Merkle root and sizes. Whether the chunks actually match the
+    /// Merkle root is up to the caller.
+    pub fn check(header: &FileHeader, trailer: &FileTrailer) -> Self {
+        let (Some(signature), Some(signer)) = (&trailer.signature, &trailer.signer) else {
+            return Self::Unsigned;
+        };
+        let verified = (|| -> Result<VerifyKey> {
+            let signer: VerifyKey = signer.parse()?;
+            let signature = Signature::from_bytes(&from_hex(signature)?);
+            signer.0.verify(&signed_message(header, trailer)?, &signature)?;
+            Ok(signer)
+        })();
+        match verified {
+            Ok(signer) => Self::Valid(signer),
+            Err(_) => Self::Invalid,
+        }
+    }
+}
+
+/// In reports, `{"status": "valid", "signer": "B2V-VERIFY-KEY-..."}`
+impl Serialize for SignatureStatus {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let (status, signer) = match self {
+            Self::Unsigned => ("unsigned", None),
+            Self::Invalid => ("invalid", None),
+            Self::Valid(signer) => ("valid", Some(signer.to_string())),
+        };
+        let mut state = serializer.serialize_struct("SignatureStatus", 2)?;
+        state.serialize_field("status", status)?;
+        state.serialize_field("signer", &signer)?;
+        state.end()
+    }
+}
+
+/// The bytes a signature covers: the header (with the hash still pending,
+/// as in the first header frame), the content hash, the Merkle root of the
+/// chunks and the chunk and byte counts.
+fn signed_message(header: &FileHeader, trailer: &FileTrailer) -> Result<Vec<u8>> {
+    let merkle_root = trailer.merkle_root.as_deref().context("Trailer has no Merkle root")?;
+    let header = FileHeader { sha256_hash: "PENDING".to_string(), ..header.clone() };
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(&Sha256::digest(header.to_bytes()?));
+    message.extend_from_slice(&from_hex::<32>(&trailer.sha256_hash)?);
+    message.extend_from_slice(&from_hex::<32>(merkle_root)?);
+    message.write_u64::<BigEndian>(trailer.total_chunks)?;
+    message.write_u64::<BigEndian>(trailer.byte_length)?;
+    Ok(message)
+}
+
+/// Merkle tree over the chunks as stored (the data shards of each chunk,
+/// encrypted if the video is), so the root can be checked without the key
+/// and a single chunk can be proven part of a signed video.
+///
+/// Leaves are SHA-256(0x00 || chunk), nodes SHA-256(0x01 || left || right);
+/// an odd node out is carried up to the next level as it is.
+#[derive(Debug, Default)]
+pub struct MerkleTree {
+    leaves: Vec<Option<[u8; 32]>>,
+}
+
+impl MerkleTree {
+    /// Adds the next chunk, or `None` for a chunk that couldn't be recovered.
+    pub fn push(&mut self, chunk: Option<&[u8]>) {
+        self.leaves.push(chunk.map(|data| {
+            let mut hasher = Sha256::new();
+            hasher.update([0x00]);
+            hasher.update(data);
+            hasher.finalize().into()
+        }));
+    }
+
+    /// The root as hex, or `None` if any chunk is missing. A video without
+    /// chunks has the hash of nothing as its root.
+    pub fn root(&self) -> Option<String> {
+        let mut level = self.leaves.iter().copied().collect::<Option<Vec<[u8; 32]>>>()?;
+        if level.is_empty() {
+            return Some(to_hex(&Sha256::digest([])));
+        }
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => {
+                        let mut hasher = Sha256::new();
+                        hasher.update([0x01]);
+                        hasher.update(left);
+                        hasher.update(right);
+                        hasher.finalize().into()
+                    }
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+        Some(to_hex(&level[0]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::BlockSize;
+    use crate::modulation::Modulation;
+    use crate::utils::{EncodingParams, DEFAULT_FPS, DEFAULT_GEOMETRY};
+
+    #[test]
+    fn test_signature_covers_header_and_trailer() {
+        let params = EncodingParams {
+            geometry: DEFAULT_GEOMETRY,
+            fps: DEFAULT_FPS,
+            block_size: BlockSize::square(4),
+            modulation: Modulation::BINARY,
+            data_shards: 10,
+            parity_shards: 2,
+        };
+        let header = FileHeader::new("a.bin".to_string(), 3, "PENDING".to_string(), &params);
+        let mut tree = MerkleTree::default();
+        for chunk in [&b"one"[..], b"two", b"three"] {
+            tree.push(Some(chunk));
+        }
+        let hash = to_hex(&Sha256::digest(b"abc"));
+        let mut trailer = FileTrailer::new(hash.clone(), 3, 3);
+        assert_eq!(SignatureStatus::check(&header, &trailer), SignatureStatus::Unsigned);
+
+        let key = SignKey::generate().unwrap();
+        key.sign(&header, &mut trailer, tree.root().unwrap()).unwrap();
+        let trailer = FileTrailer::from_bytes(&trailer.to_bytes().unwrap()).unwrap();
+        assert_eq!(SignatureStatus::check(&header, &trailer), SignatureStatus::Valid(key.verify_key()));
+
+        // The final header copy, with the real hash, checks out the same
+        let final_header = FileHeader { sha256_hash: hash, ..header.clone() };
+        assert_eq!(SignatureStatus::check(&final_header, &trailer), SignatureStatus::Valid(key.verify_key()));
+
+        // Any change to what was signed breaks it
+        let other = FileHeader::new("b.bin".to_string(), 3, "PENDING".to_string(), &params);
+        assert_eq!(SignatureStatus::check(&other, &trailer), SignatureStatus::Invalid);
+        let mut forged = trailer.clone();
+        forged.total_chunks = 2;
+        assert_eq!(SignatureStatus::check(&header, &forged), SignatureStatus::Invalid);
+        let mut forged = trailer.clone();
+        forged.signer = Some(SignKey::generate().unwrap().verify_key().to_string());
+        assert_eq!(SignatureStatus::check(&header, &forged), SignatureStatus::Invalid);
+
+        // Keys survive their text form
+        let verify_key = key.verify_key().to_string();
+        assert_eq!(verify_key.parse::<VerifyKey>().unwrap(), key.verify_key());
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let leaf = |data: &[u8]| -> [u8; 32] { Sha256::digest([&[0x00], data].concat()).into() };
+        let node = |l: [u8; 32], r: [u8; 32]| -> [u8; 32] { Sha256::digest([&[0x01], &l[..], &r[..]].concat()).into() };
+
+        let mut tree = MerkleTree::default();
+        tree.push(Some(b"a"));
+        assert_eq!(tree.root(), Some(to_hex(&leaf(b"a"))));
+        tree.push(Some(b"b"));
+        tree.push(Some(b"c"));
+        let expected = node(node(leaf(b"a"), leaf(b"b")), leaf(b"c"));
+        assert_eq!(tree.root(), Some(to_hex(&expected)));
+
+        tree.push(None);
+        assert_eq!(tree.root(), None);
+    }
+}
@@ -1,17 +1,323 @@
+use crate::archive::ARCHIVE_FORMAT;
+use crate::calibration::CALIBRATION_HEIGHT;
+use crate::compression::Compression;
+use crate::crypto::{Encryption, Recipients, TAG_SIZE};
+use crate::layout::{BlockSize, Layout};
+use crate::metadata::Metadata;
+use crate::modulation::Modulation;
 use anyhow::{Context, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Read;
+use std::process::Command;
+use std::str::FromStr;
 
 // Constants
 pub const MAGIC_NUMBER: u64 = 0x4554_524E_4C53_5452; // "ETRNLSTR" in hex
+pub const TRAILER_MAGIC: u64 = 0x4554_524E_4C45_4E44; // "ETRNLEND" in hex
 pub const HEADER_SIZE: usize = 1024; // Fixed header size
-pub const VERSION: u32 = 1;
+pub const FRAME_HEADER_SIZE: usize = 18; // frame (u32) + chunk (u32) + shard (u16) + 2x crc32
+pub const FRAME_HEADER_COPIES: usize = 3; // Repetitions in the strip, for majority voting
+pub const VERSION: u32 = 2; // Major header format version, bumped on incompatible layout changes
+pub const MINOR_VERSION: u32 = 3; // Bumped on additions older readers can safely ignore
+pub const LEGACY_JSON_VERSION: u32 = 1; // Headers were serde JSON up to this version
+
+// Header flags. Unknown bits change how the video must be read, so readers refuse them.
+pub const FLAG_COLOR: u32 = 1 << 0;
+const KNOWN_FLAGS: u32 = FLAG_COLOR;
+
+// Extension tags with this bit set can't be skipped by readers that don't know them
+pub const CRITICAL_EXTENSION: u16 = 0x8000;
+pub const EXT_ENCRYPTION: u16 = CRITICAL_EXTENSION | 0x0001; // Chunks are encrypted, see `crypto::Encryption`
+pub const EXT_RECIPIENTS: u16 = CRITICAL_EXTENSION | 0x0002; // Chunks are encrypted, see `crypto::Recipients`
+pub const EXT_COMPRESSION: u16 = CRITICAL_EXTENSION | 0x0003; // Input is compressed, see `compression::Compression`
+pub const EXT_ARCHIVE: u16 = CRITICAL_EXTENSION | 0x0004; // Input is several files, see `archive::Manifest`
+pub const EXT_INDEX: u16 = 0x0005; // Index frames follow the header, see `index::Index`
+pub const EXT_METADATA: u16 = 0x0006; // Permissions, times and attributes of a single file, see `metadata::Metadata`
+pub const EXT_HEADER_INTERVAL: u16 = 0x0007; // Chunks between header copies (u64), so the frame count can be worked out
+const KNOWN_CRITICAL_EXTENSIONS: &[u16] = &[EXT_ENCRYPTION, EXT_RECIPIENTS, EXT_COMPRESSION, EXT_ARCHIVE];
 
 // Video Settings
-pub const VIDEO_WIDTH: usize = 1920;
-pub const VIDEO_HEIGHT: usize = 1080;
-pub const FRAME_RATE: usize = 30;
+pub const DEFAULT_GEOMETRY: Geometry = Geometry::new(1920, 1080);
+pub const DEFAULT_FPS: u32 = 30;
 pub const PIXEL_FORMAT: &str = "rgb24";
+pub const BOOTSTRAP_BLOCK_SIZE: usize = 4; // Header and trailer frames always use this
+pub const DEFAULT_HEADER_INTERVAL: u64 = 16; // Chunks between header copies
+
+// Frame Header Strip: top rows of every data/parity frame, fixed block size
+pub const STRIP_BLOCK_SIZE: usize = 8;
+
+/// The bootstrap grid is defined in this reference geometry (480x270 blocks,
+/// portrait if the frame is) and stretched over whatever size the frame really
+/// has, so the header can be read before we know how the video was encoded or
+/// whether a host rescaled it.
+pub fn bootstrap_geometry(frame: Geometry) -> Geometry {
+    if frame.height > frame.width {
+        Geometry::new(1080, 1920)
+    } else {
+        Geometry::new(1920, 1080)
+    }
+}
+
+/// Header/trailer data area of a bootstrap grid, below the calibration band.
+pub fn bootstrap_layout(grid: Geometry) -> Layout {
+    Layout::new(grid.width, CALIBRATION_HEIGHT..grid.height, BlockSize::square(BOOTSTRAP_BLOCK_SIZE))
+}
+
+/// Height in pixels of the frame header strip for a given frame width.
+pub fn strip_height(width: usize) -> usize {
+    let bits = FRAME_HEADER_SIZE * FRAME_HEADER_COPIES * 8;
+    let cols = Layout::new(width, 0..0, BlockSize::square(STRIP_BLOCK_SIZE)).cols();
+    bits.div_ceil(cols) * STRIP_BLOCK_SIZE
+}
+
+/// The frame header strip at the top of a data/parity frame.
+pub fn strip_layout(width: usize) -> Layout {
+    Layout::new(width, 0..strip_height(width), BlockSize::square(STRIP_BLOCK_SIZE))
+}
+
+/// The area below the strip of a data frame, where the shard goes.
+pub fn body_layout(geometry: Geometry, block_size: BlockSize) -> Layout {
+    let strip_height = strip_height(geometry.width).min(geometry.height);
+    Layout::new(geometry.width, strip_height..geometry.height, block_size)
+}
+
+/// Payload bytes that fit in the area below the strip of a data frame.
+pub fn data_frame_capacity(geometry: Geometry, block_size: BlockSize, modulation: Modulation) -> usize {
+    body_layout(geometry, block_size).capacity(modulation.bits_per_block())
+}
+
+/// Bitwise majority vote across equally sized copies of the same bytes.
+pub fn majority_vote(copies: &[&[u8]]) -> Vec<u8> {
+    let len = copies.iter().map(|c| c.len()).min().unwrap_or(0);
+    (0..len)
+        .map(|i| {
+            let mut byte = 0u8;
+            for bit in 0..8 {
+                let ones = copies.iter().filter(|c| (c[i] >> bit) & 1 == 1).count();
+                if ones * 2 > copies.len() {
+                    byte |= 1 << bit;
+                }
+            }
+            byte
+        })
+        .collect()
+}
+
+/// Frame dimensions in pixels.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Geometry {
+    pub const fn new(width: usize, height: usize) -> Self {
+        Self { width, height }
+    }
+
+    /// Size of one RGB24 frame in bytes.
+    pub fn frame_size(&self) -> usize {
+        self.width * self.height * 3
+    }
+}
+
+impl fmt::Display for Geometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl FromStr for Geometry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (w, h) = s
+            .trim()
+            .split_once('x')
+            .context("Expected WIDTHxHEIGHT")?;
+        let geometry = Geometry::new(w.parse().context("Invalid width")?, h.parse().context("Invalid height")?);
+        if geometry.width == 0 || geometry.height == 0 {
+            return Err(anyhow::anyhow!("Resolution must be non-zero"));
+        }
+        Ok(geometry)
+    }
+}
+
+/// Asks ffprobe for the resolution of the first video stream.
+pub fn probe_geometry(path: &str) -> Result<Geometry> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v", "error",
+            "-select_streams", "v:0",
+            "-show_entries", "stream=width,height",
+            "-of", "csv=s=x:p=0",
+            path,
+        ])
+        .output()
+        .context("Failed to spawn ffprobe")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("ffprobe failed on {}", path));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .lines()
+        .next()
+        .context("ffprobe reported no video stream")?
+        .parse()
+        .context("Failed to parse ffprobe resolution")
+}
+
+/// Number of frames in the video, counted by ffprobe from the container's
+/// packets, without decoding them.
+pub fn probe_frame_count(path: &str) -> Result<u64> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v", "error",
+            "-select_streams", "v:0",
+            "-count_packets",
+            "-show_entries", "stream=nb_read_packets",
+            "-of", "csv=p=0",
+            path,
+        ])
+        .output()
+        .context("Failed to spawn ffprobe")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("ffprobe failed on {}", path));
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .context("Failed to parse ffprobe frame count")
+}
+
+/// Frame rate of the first video stream as the container reports it, which
+/// is not the one in the header if a host re-encoded the video.
+pub fn probe_frame_rate(path: &str) -> Result<f64> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v", "error",
+            "-select_streams", "v:0",
+            "-show_entries", "stream=avg_frame_rate,r_frame_rate",
+            "-of", "default=noprint_wrappers=1",
+            path,
+        ])
+        .output()
+        .context("Failed to spawn ffprobe")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("ffprobe failed on {}", path));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let rate = |key: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+            .and_then(parse_rate)
+    };
+    // The average is what timestamps follow, unless the container lacks it (0/0)
+    rate("avg_frame_rate")
+        .or_else(|| rate("r_frame_rate"))
+        .context("ffprobe reported no frame rate")
+}
+
+/// A rate as ffprobe writes it, e.g. `30000/1001`. `None` unless positive.
+fn parse_rate(rate: &str) -> Option<f64> {
+    let (num, den) = rate.trim().split_once('/').unwrap_or((rate.trim(), "1"));
+    let rate = num.parse::<f64>().ok()? / den.parse::<f64>().ok()?;
+    (rate.is_finite() && rate > 0.0).then_some(rate)
+}
+
+/// Everything that decides how data is laid out in the video. Chosen on the
+/// encode command line, recorded in the header, and read back by the decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodingParams {
+    pub geometry: Geometry,
+    pub fps: u32,
+    pub block_size: BlockSize,
+    pub modulation: Modulation,
+    pub data_shards: usize,
+    pub parity_shards: usize,
+}
+
+impl EncodingParams {
+    /// Payload bytes per data/parity frame, i.e. the shard size.
+    pub fn shard_size(&self) -> usize {
+        data_frame_capacity(self.geometry, self.block_size, self.modulation)
+    }
+
+    /// Input bytes consumed per Reed-Solomon chunk.
+    pub fn chunk_size(&self) -> usize {
+        self.shard_size() * self.data_shards
+    }
+
+    pub fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        // Bootstrap blocks are stretched from the grid, and must stay at least a pixel
+        let grid = bootstrap_geometry(self.geometry);
+        if self.geometry.width * BOOTSTRAP_BLOCK_SIZE < grid.width || self.geometry.height * BOOTSTRAP_BLOCK_SIZE < grid.height {
+            return Err(anyhow::anyhow!(
+                "Resolution {} too small, it must be at least {}x{}",
+                self.geometry,
+                grid.width.div_ceil(BOOTSTRAP_BLOCK_SIZE),
+                grid.height.div_ceil(BOOTSTRAP_BLOCK_SIZE)
+            ));
+        }
+        if self.fps == 0 {
+            return Err(anyhow::anyhow!("Frame rate must be non-zero"));
+        }
+        if self.shard_size() == 0 {
+            return Err(anyhow::anyhow!(
+                "Block size {} leaves no room for data at {}",
+                self.block_size,
+                self.geometry
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_width() -> u32 {
+    DEFAULT_GEOMETRY.width as u32
+}
+
+fn default_height() -> u32 {
+    DEFAULT_GEOMETRY.height as u32
+}
+
+fn default_fps() -> u32 {
+    DEFAULT_FPS
+}
 
+fn default_bits_per_block() -> u8 {
+    Modulation::BINARY.bits_per_channel
+}
+
+/// One entry of the header's TLV extension area. Newer writers can add
+/// entries without bumping the major version: readers skip tags they don't
+/// know, unless the tag is marked `CRITICAL_EXTENSION`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub tag: u16,
+    pub value: Vec<u8>,
+}
+
+/// Written into every header frame. Since format version 2 it is stored in
+/// the compact binary layout below; version 1 videos carry it as serde JSON,
+/// which `from_bytes` still reads.
+///
+/// ```text
+/// magic "ETRNLSTR"   8    file size          8    bits per channel   1
+/// major version      2    width, height      4+4  data shards        2
+/// minor version      2    fps                4    parity shards      2
+/// flags              4    block width/height 2+2  SHA-256 (0: pending) 32
+/// filename           2 + n bytes of UTF-8
+/// extension count    2, then per extension: tag 2, length 2, value
+/// CRC32 of all the above, zero padding up to HEADER_SIZE
+/// ```
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileHeader {
     pub magic: u64,
@@ -22,41 +328,277 @@ pub struct FileHeader {
     pub sha256_hash: String,
     pub data_shards: usize,
     pub parity_shards: usize,
+    // Only set for non-square blocks, whose width is `block_size`
+    #[serde(default)]
+    pub block_height: Option<u32>,
+    // Geometry the video was encoded at, before any rescaling by a host
+    #[serde(default = "default_width")]
+    pub width: u32,
+    #[serde(default = "default_height")]
+    pub height: u32,
+    #[serde(default = "default_fps")]
+    pub fps: u32,
+    // Bits per channel sample; in colour mode each block carries three of these
+    #[serde(default = "default_bits_per_block")]
+    pub bits_per_block: u8,
+    #[serde(default)]
+    pub color: bool,
+    #[serde(default)]
+    pub minor_version: u32,
+    #[serde(default)]
+    pub extensions: Vec<Extension>,
 }
 
 impl FileHeader {
     pub fn new(
         original_filename: String,
         file_size: u64,
-        block_size: u32,
         sha256_hash: String,
-        data_shards: usize,
-        parity_shards: usize,
+        params: &EncodingParams,
     ) -> Self {
         Self {
             magic: MAGIC_NUMBER,
             version: VERSION,
             original_filename,
             file_size,
-            block_size,
+            block_size: params.block_size.width as u32,
             sha256_hash,
-            data_shards,
-            parity_shards,
+            data_shards: params.data_shards,
+            parity_shards: params.parity_shards,
+            block_height: (!params.block_size.is_square()).then_some(params.block_size.height as u32),
+            width: params.geometry.width as u32,
+            height: params.geometry.height as u32,
+            fps: params.fps,
+            bits_per_block: params.modulation.bits_per_channel,
+            color: params.modulation.color,
+            minor_version: MINOR_VERSION,
+            extensions: Vec::new(),
+        }
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        Geometry::new(self.width as usize, self.height as usize)
+    }
+
+    pub fn block_size(&self) -> BlockSize {
+        BlockSize {
+            width: self.block_size as usize,
+            height: self.block_height.unwrap_or(self.block_size) as usize,
+        }
+    }
+
+    pub fn params(&self) -> Result<EncodingParams> {
+        let block_size = self.block_size();
+        if block_size.width == 0 || block_size.height == 0 {
+            return Err(anyhow::anyhow!("Block size must be non-zero"));
+        }
+        Ok(EncodingParams {
+            geometry: self.geometry(),
+            fps: self.fps,
+            block_size,
+            modulation: Modulation::new(self.bits_per_block, self.color)?,
+            data_shards: self.data_shards,
+            parity_shards: self.parity_shards,
+        })
+    }
+
+    pub fn extension(&self, tag: u16) -> Option<&[u8]> {
+        self.extensions.iter().find(|e| e.tag == tag).map(|e| e.value.as_slice())
+    }
+
+    pub fn set_extension(&mut self, tag: u16, value: Vec<u8>) {
+        self.extensions.retain(|e| e.tag != tag);
+        self.extensions.push(Extension { tag, value });
+    }
+
+    /// How the chunks are encrypted with a passphrase, if they are.
+    pub fn encryption(&self) -> Result<Option<Encryption>> {
+        self.extension(EXT_ENCRYPTION).map(Encryption::from_bytes).transpose()
+    }
+
+    /// Who the chunks are encrypted to, if anyone.
+    pub fn recipients(&self) -> Result<Option<Recipients>> {
+        self.extension(EXT_RECIPIENTS).map(Recipients::from_bytes).transpose()
+    }
+
+    /// How the input was compressed before it was cut into chunks, if it was.
+    pub fn compression(&self) -> Result<Option<Compression>> {
+        self.extension(EXT_COMPRESSION).map(Compression::from_bytes).transpose()
+    }
+
+    /// Whether the stream is an archive of several files, rather than one file.
+    pub fn is_archive(&self) -> Result<bool> {
+        match self.extension(EXT_ARCHIVE) {
+            None => Ok(false),
+            Some([ARCHIVE_FORMAT]) => Ok(true),
+            Some(value) => Err(anyhow::anyhow!("Unsupported archive format {:?}", value)),
         }
     }
 
+    /// How many index frames follow the first header frame, if any.
+    pub fn index_frames(&self) -> Result<Option<u32>> {
+        self.extension(EXT_INDEX)
+            .map(|mut value| value.read_u32::<BigEndian>().context("Invalid index extension"))
+            .transpose()
+    }
+
+    /// Chunks between header copies, if recorded. 0 means none.
+    pub fn header_interval(&self) -> Result<Option<u64>> {
+        self.extension(EXT_HEADER_INTERVAL)
+            .map(|mut value| value.read_u64::<BigEndian>().context("Invalid header interval extension"))
+            .transpose()
+    }
+
+    /// What else there is to restore of a single file, if it was recorded.
+    pub fn metadata(&self) -> Result<Option<Metadata>> {
+        self.extension(EXT_METADATA).map(Metadata::from_bytes).transpose()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.extension(EXT_ENCRYPTION).is_some() || self.extension(EXT_RECIPIENTS).is_some()
+    }
+
+    /// Input bytes carried per chunk: the chunk size, less the tag when the
+    /// chunks are encrypted.
+    pub fn payload_size(&self) -> Result<usize> {
+        let chunk_size = self.params()?.chunk_size();
+        let overhead = if self.is_encrypted() { TAG_SIZE } else { 0 };
+        if chunk_size <= overhead {
+            return Err(anyhow::anyhow!("Chunks of {} bytes are too small to encrypt", chunk_size));
+        }
+        Ok(chunk_size - overhead)
+    }
+
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        let json = serde_json::to_string(self).context("Failed to serialize header")?;
-        let bytes = json.as_bytes();
+        let block_size = self.block_size();
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.write_u64::<BigEndian>(self.magic)?;
+        bytes.write_u16::<BigEndian>(VERSION as u16)?;
+        bytes.write_u16::<BigEndian>(self.minor_version as u16)?;
+        bytes.write_u32::<BigEndian>(if self.color { FLAG_COLOR } else { 0 })?;
+        bytes.write_u64::<BigEndian>(self.file_size)?;
+        bytes.write_u32::<BigEndian>(self.width)?;
+        bytes.write_u32::<BigEndian>(self.height)?;
+        bytes.write_u32::<BigEndian>(self.fps)?;
+        bytes.write_u16::<BigEndian>(block_size.width.try_into().context("Block width too large")?)?;
+        bytes.write_u16::<BigEndian>(block_size.height.try_into().context("Block height too large")?)?;
+        bytes.write_u8(self.bits_per_block)?;
+        bytes.write_u16::<BigEndian>(self.data_shards.try_into().context("Too many data shards")?)?;
+        bytes.write_u16::<BigEndian>(self.parity_shards.try_into().context("Too many parity shards")?)?;
+        bytes.extend_from_slice(&hash_to_bytes(&self.sha256_hash)?);
+        write_field(&mut bytes, self.original_filename.as_bytes())?;
+        bytes.write_u16::<BigEndian>(self.extensions.len().try_into().context("Too many header extensions")?)?;
+        for extension in &self.extensions {
+            bytes.write_u16::<BigEndian>(extension.tag)?;
+            write_field(&mut bytes, &extension.value)?;
+        }
+        let crc = crc32fast::hash(&bytes);
+        bytes.write_u32::<BigEndian>(crc)?;
         if bytes.len() > HEADER_SIZE {
-            return Err(anyhow::anyhow!("Header too large"));
+            return Err(anyhow::anyhow!(
+                "Header too large ({} of {} bytes): shorten the file name or use fewer recipients",
+                bytes.len(),
+                HEADER_SIZE
+            ));
         }
-        let mut padded = vec![0u8; HEADER_SIZE];
-        padded[..bytes.len()].copy_from_slice(bytes);
-        Ok(padded)
+        bytes.resize(HEADER_SIZE, 0);
+        Ok(bytes)
     }
 
+    /// Reads a header in whichever format it was written: the binary layout,
+    /// dispatched on its major version, or a legacy v1 JSON header.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if Self::is_binary(bytes) {
+            Self::from_binary(bytes)
+        } else if bytes.first() == Some(&b'{') {
+            Self::from_json(bytes)
+        } else {
+            Err(anyhow::anyhow!("Invalid Magic Number"))
+        }
+    }
+
+    /// Whether `bytes` start like a binary header, whatever its version.
+    pub fn is_binary(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC_NUMBER.to_be_bytes())
+    }
+
+    fn from_binary(bytes: &[u8]) -> Result<Self> {
+        let mut reader = &bytes[8..];
+        let version = reader.read_u16::<BigEndian>()? as u32;
+        let minor_version = reader.read_u16::<BigEndian>()? as u32;
+        if version != VERSION {
+            let hint = if version > VERSION { ", please upgrade b2v to decode this video" } else { "" };
+            return Err(anyhow::anyhow!(
+                "Unsupported header format version {}.{}: this b2v reads version {}.x{}",
+                version,
+                minor_version,
+                VERSION,
+                hint
+            ));
+        }
+
+        let flags = reader.read_u32::<BigEndian>()?;
+        let file_size = reader.read_u64::<BigEndian>()?;
+        let width = reader.read_u32::<BigEndian>()?;
+        let height = reader.read_u32::<BigEndian>()?;
+        let fps = reader.read_u32::<BigEndian>()?;
+        let block_width = reader.read_u16::<BigEndian>()? as u32;
+        let block_height = reader.read_u16::<BigEndian>()? as u32;
+        let bits_per_block = reader.read_u8()?;
+        let data_shards = reader.read_u16::<BigEndian>()? as usize;
+        let parity_shards = reader.read_u16::<BigEndian>()? as usize;
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash)?;
+        let original_filename = String::from_utf8(read_field(&mut reader)?.to_vec()).context("Invalid UTF-8 in filename")?;
+        let extension_count = reader.read_u16::<BigEndian>()?;
+        let extensions = (0..extension_count)
+            .map(|_| {
+                let tag = reader.read_u16::<BigEndian>()?;
+                Ok(Extension { tag, value: read_field(&mut reader)?.to_vec() })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let end = bytes.len() - reader.len();
+        if reader.read_u32::<BigEndian>()? != crc32fast::hash(&bytes[..end]) {
+            return Err(anyhow::anyhow!("Header CRC mismatch"));
+        }
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(anyhow::anyhow!(
+                "Header uses flags {:#x} unknown to this b2v, please upgrade to decode this video",
+                flags & !KNOWN_FLAGS
+            ));
+        }
+        if let Some(extension) = extensions
+            .iter()
+            .find(|e| e.tag & CRITICAL_EXTENSION != 0 && !KNOWN_CRITICAL_EXTENSIONS.contains(&e.tag))
+        {
+            return Err(anyhow::anyhow!(
+                "Header requires extension {:#06x} unknown to this b2v, please upgrade to decode this video",
+                extension.tag
+            ));
+        }
+
+        Ok(Self {
+            magic: MAGIC_NUMBER,
+            version,
+            original_filename,
+            file_size,
+            block_size: block_width,
+            sha256_hash: hash_from_bytes(&hash),
+            data_shards,
+            parity_shards,
+            block_height: (block_height != block_width).then_some(block_height),
+            width,
+            height,
+            fps,
+            bits_per_block,
+            color: flags & FLAG_COLOR != 0,
+            minor_version,
+            extensions,
+        })
+    }
+
+    fn from_json(bytes: &[u8]) -> Result<Self> {
         // Find the first null byte to determine end of JSON string
         let len = bytes.iter().position(|&x| x == 0).unwrap_or(bytes.len());
         let json_str = std::str::from_utf8(&bytes[..len]).context("Invalid UTF-8 in header")?;
@@ -64,24 +606,215 @@ impl FileHeader {
         if header.magic != MAGIC_NUMBER {
             return Err(anyhow::anyhow!("Invalid Magic Number"));
         }
+        if header.version != LEGACY_JSON_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported JSON header version {}: only version {} headers were JSON",
+                header.version,
+                LEGACY_JSON_VERSION
+            ));
+        }
         Ok(header)
     }
 }
 
+/// Writes a length-prefixed byte field of the binary header.
+pub(crate) fn write_field(bytes: &mut Vec<u8>, field: &[u8]) -> Result<()> {
+    bytes.write_u16::<BigEndian>(field.len().try_into().context("Header field too long")?)?;
+    bytes.extend_from_slice(field);
+    Ok(())
+}
+
+/// Reads a length-prefixed byte field of the binary header.
+pub(crate) fn read_field<'a>(reader: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = reader.read_u16::<BigEndian>()? as usize;
+    if reader.len() < len {
+        return Err(anyhow::anyhow!("Header field truncated"));
+    }
+    let (field, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(field)
+}
+
+/// The binary header stores the hash as raw bytes, all zero while the encoder
+/// is still streaming and only knows it as "PENDING".
+fn hash_to_bytes(hash: &str) -> Result<[u8; 32]> {
+    if hash == "PENDING" {
+        return Ok([0u8; 32]);
+    }
+    from_hex(hash).with_context(|| format!("Invalid SHA-256 hash: {}", hash))
+}
+
+fn hash_from_bytes(bytes: &[u8; 32]) -> String {
+    if bytes.iter().all(|&b| b == 0) {
+        return "PENDING".to_string();
+    }
+    to_hex(bytes)
+}
+
+/// Lowercase hex, as used for hashes and keys.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses exactly `N` bytes of hex.
+pub fn from_hex<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return Err(anyhow::anyhow!("Expected {} hex digits, got {:?}", N * 2, hex));
+    }
+    let mut bytes = [0u8; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16).context("Invalid hex digit")?;
+    }
+    Ok(bytes)
+}
+
+/// Written as the last frame of the video, once the whole input has been
+/// streamed and the real hash is known.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileTrailer {
+    pub magic: u64,
+    pub sha256_hash: String,
+    pub total_chunks: u64,
+    pub byte_length: u64,
+    /// Root of the Merkle tree over the stored chunks, see `signing::MerkleTree`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<String>,
+    /// Ed25519 signature over the header and the fields above, in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Verify key of the signer, `B2V-VERIFY-KEY-...`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer: Option<String>,
+}
+
+impl FileTrailer {
+    pub fn new(sha256_hash: String, total_chunks: u64, byte_length: u64) -> Self {
+        Self {
+            magic: TRAILER_MAGIC,
+            sha256_hash,
+            total_chunks,
+            byte_length,
+            merkle_root: None,
+            signature: None,
+            signer: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let json = serde_json::to_string(self).context("Failed to serialize trailer")?;
+        let bytes = json.as_bytes();
+        if bytes.len() > HEADER_SIZE {
+            return Err(anyhow::anyhow!("Trailer too large"));
+        }
+        let mut padded = vec![0u8; HEADER_SIZE];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        Ok(padded)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let len = bytes.iter().position(|&x| x == 0).unwrap_or(bytes.len());
+        let json_str = std::str::from_utf8(&bytes[..len]).context("Invalid UTF-8 in trailer")?;
+        let trailer: FileTrailer = serde_json::from_str(json_str).context("Failed to deserialize trailer")?;
+        if trailer.magic != TRAILER_MAGIC {
+            return Err(anyhow::anyhow!("Invalid Trailer Magic Number"));
+        }
+        Ok(trailer)
+    }
+}
+
+/// Rendered in the strip at the top of every data/parity frame. The indices
+/// let the decoder regroup shards when a platform drops or duplicates frames,
+/// and the payload CRC turns a damaged shard into an erasure for Reed-Solomon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub frame_number: u32,
+    pub chunk_index: u32,
+    pub shard_index: u16,
+    pub payload_crc32: u32,
+}
+
+impl FrameHeader {
+    pub fn new(frame_number: u32, chunk_index: u32, shard_index: u16, payload: &[u8]) -> Self {
+        Self {
+            frame_number,
+            chunk_index,
+            shard_index,
+            payload_crc32: crc32fast::hash(payload),
+        }
+    }
+
+    pub fn verify(&self, payload: &[u8]) -> bool {
+        self.payload_crc32 == crc32fast::hash(payload)
+    }
+
+    pub fn to_bytes(self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(FRAME_HEADER_SIZE);
+        bytes.write_u32::<BigEndian>(self.frame_number)?;
+        bytes.write_u32::<BigEndian>(self.chunk_index)?;
+        bytes.write_u16::<BigEndian>(self.shard_index)?;
+        bytes.write_u32::<BigEndian>(self.payload_crc32)?;
+        let header_crc = Self::fields_crc(&bytes);
+        bytes.write_u32::<BigEndian>(header_crc)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < FRAME_HEADER_SIZE {
+            return Err(anyhow::anyhow!("Frame header truncated"));
+        }
+        let (fields, mut crc) = bytes[..FRAME_HEADER_SIZE].split_at(FRAME_HEADER_SIZE - 4);
+        if crc.read_u32::<BigEndian>()? != Self::fields_crc(fields) {
+            return Err(anyhow::anyhow!("Frame header CRC mismatch"));
+        }
+        let mut fields = fields;
+        Ok(Self {
+            frame_number: fields.read_u32::<BigEndian>()?,
+            chunk_index: fields.read_u32::<BigEndian>()?,
+            shard_index: fields.read_u16::<BigEndian>()?,
+            payload_crc32: fields.read_u32::<BigEndian>()?,
+        })
+    }
+
+    /// CRC of the header fields, seeded with the magic number. Plain CRC32
+    /// accepts four 0xFF bytes followed by zeros, which flat areas such as the
+    /// calibration band of a bootstrap frame can read as.
+    fn fields_crc(fields: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&MAGIC_NUMBER.to_be_bytes());
+        hasher.update(fields);
+        hasher.finalize()
+    }
+
+    /// Recovers the header from the repeated copies in a strip: majority vote
+    /// first, then each copy on its own in case the damage is concentrated.
+    pub fn from_strip(strip: &[u8]) -> Option<Self> {
+        let copies: Vec<&[u8]> = strip
+            .chunks_exact(FRAME_HEADER_SIZE)
+            .take(FRAME_HEADER_COPIES)
+            .collect();
+        if let Ok(header) = Self::from_bytes(&majority_vote(&copies)) {
+            return Some(header);
+        }
+        copies.iter().find_map(|c| Self::from_bytes(c).ok())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_header_serialization() {
-        let original = FileHeader::new(
-            "test_file.txt".to_string(),
-            12345,
-            4,
-            "hash123".to_string(),
-            10,
-            2,
-        );
+        let params = EncodingParams {
+            geometry: Geometry::new(1280, 720),
+            fps: 24,
+            block_size: BlockSize { width: 4, height: 2 },
+            modulation: Modulation::new(3, true).unwrap(),
+            data_shards: 10,
+            parity_shards: 2,
+        };
+        let hash = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string();
+        let original = FileHeader::new("test_file.txt".to_string(), 12345, hash.clone(), &params);
 
         let bytes = original.to_bytes().expect("Serialization failed");
         assert_eq!(bytes.len(), HEADER_SIZE);
@@ -95,5 +828,169 @@ mod tests {
         assert_eq!(decoded.block_size, 4);
         assert_eq!(decoded.data_shards, 10);
         assert_eq!(decoded.parity_shards, 2);
+        assert_eq!(decoded.sha256_hash, hash);
+        assert_eq!(decoded.params().unwrap(), params);
+
+        // Square blocks and a pending hash
+        let params = EncodingParams { block_size: BlockSize::square(4), ..params };
+        let pending = FileHeader::new("a".to_string(), 1, "PENDING".to_string(), &params);
+        let decoded = FileHeader::from_bytes(&pending.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.block_height, None);
+        assert_eq!(decoded.sha256_hash, "PENDING");
+    }
+
+    #[test]
+    fn test_header_versioning() {
+        let params = EncodingParams {
+            geometry: DEFAULT_GEOMETRY,
+            fps: DEFAULT_FPS,
+            block_size: BlockSize::square(4),
+            modulation: Modulation::BINARY,
+            data_shards: 10,
+            parity_shards: 2,
+        };
+        let mut header = FileHeader::new("f".to_string(), 1, "PENDING".to_string(), &params);
+
+        // Extensions round-trip, unknown ones are skipped
+        header.extensions.push(Extension { tag: 0x0123, value: vec![1, 2, 3] });
+        let bytes = header.to_bytes().unwrap();
+        assert_eq!(FileHeader::from_bytes(&bytes).unwrap().extensions, header.extensions);
+
+        // ...unless marked critical
+        let mut critical = header.clone();
+        critical.extensions.push(Extension { tag: CRITICAL_EXTENSION | 0x0123, value: vec![] });
+        let error = FileHeader::from_bytes(&critical.to_bytes().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("0x8123"));
+
+        // Patches a field and recomputes the CRC behind the extension area
+        let crc_at = (0..HEADER_SIZE - 4)
+            .find(|&end| bytes[end..end + 4] == crc32fast::hash(&bytes[..end]).to_be_bytes())
+            .unwrap();
+        let patched = |offset: usize, field: &[u8]| {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + field.len()].copy_from_slice(field);
+            let crc = crc32fast::hash(&bytes[..crc_at]);
+            bytes[crc_at..crc_at + 4].copy_from_slice(&crc.to_be_bytes());
+            FileHeader::from_bytes(&bytes)
+        };
+
+        // A newer minor version is fine, a newer major version is refused
+        assert_eq!(patched(10, &(MINOR_VERSION as u16 + 1).to_be_bytes()).unwrap().minor_version, MINOR_VERSION + 1);
+        let error = patched(8, &(VERSION as u16 + 1).to_be_bytes()).unwrap_err();
+        assert!(error.to_string().contains(&format!("Unsupported header format version 3.{}", MINOR_VERSION)));
+
+        // Unknown flags are refused, damage is caught by the CRC
+        let error = patched(12, &0x8000_0001u32.to_be_bytes()).unwrap_err();
+        assert!(error.to_string().contains("flags 0x80000000"));
+        let mut damaged = bytes.clone();
+        damaged[40] ^= 0x01;
+        assert!(FileHeader::from_bytes(&damaged).unwrap_err().to_string().contains("CRC"));
+    }
+
+    #[test]
+    fn test_header_defaults_for_older_videos() {
+        // Headers written before geometry and frame rate were recorded
+        let json = br#"{"magic":4995708382902178898,"version":1,"original_filename":"a","file_size":1,"block_size":4,"sha256_hash":"PENDING","data_shards":10,"parity_shards":2}"#;
+        let decoded = FileHeader::from_bytes(json).expect("Deserialization failed");
+        assert_eq!(decoded.version, LEGACY_JSON_VERSION);
+        assert_eq!(decoded.geometry(), DEFAULT_GEOMETRY);
+        assert_eq!(decoded.fps, DEFAULT_FPS);
+        assert_eq!(decoded.block_size(), BlockSize::square(4));
+        assert_eq!(decoded.bits_per_block, 1);
+        assert!(!decoded.color);
+    }
+
+    #[test]
+    fn test_geometry_parsing() {
+        assert_eq!("1280x720".parse::<Geometry>().unwrap(), Geometry::new(1280, 720));
+        assert_eq!("1080x1920\n".parse::<Geometry>().unwrap(), Geometry::new(1080, 1920));
+        assert!("1280".parse::<Geometry>().is_err());
+        assert!("0x720".parse::<Geometry>().is_err());
+    }
+
+    #[test]
+    fn test_resolution_fits_bootstrap_grid() {
+        let params = |width, height| EncodingParams {
+            geometry: Geometry::new(width, height),
+            fps: DEFAULT_FPS,
+            block_size: BlockSize::square(4),
+            modulation: Modulation::BINARY,
+            data_shards: 10,
+            parity_shards: 2,
+        };
+        assert!(params(480, 270).validate().is_ok());
+        assert!(params(270, 480).validate().is_ok());
+        // Both sides above 270, but too narrow for the 1920 wide grid
+        assert!(params(400, 400).validate().is_err());
+        assert!(params(270, 270).validate().is_err());
+        assert!(params(479, 270).validate().is_err());
+    }
+
+    #[test]
+    fn test_frame_rate_parsing() {
+        assert_eq!(parse_rate("30/1"), Some(30.0));
+        assert_eq!(parse_rate("25"), Some(25.0));
+        assert!((parse_rate("30000/1001").unwrap() - 29.97).abs() < 0.01);
+        // What containers without a rate report
+        assert_eq!(parse_rate("0/0"), None);
+        assert_eq!(parse_rate("N/A"), None);
+    }
+
+    #[test]
+    fn test_trailer_serialization() {
+        let original = FileTrailer::new("abc123".to_string(), 7, 98765);
+
+        let bytes = original.to_bytes().expect("Serialization failed");
+        assert_eq!(bytes.len(), HEADER_SIZE);
+
+        let decoded = FileTrailer::from_bytes(&bytes).expect("Deserialization failed");
+        assert_eq!(decoded.sha256_hash, "abc123");
+        assert_eq!(decoded.total_chunks, 7);
+        assert_eq!(decoded.byte_length, 98765);
+
+        // A header frame must never be mistaken for a trailer
+        let params = EncodingParams {
+            geometry: DEFAULT_GEOMETRY,
+            fps: DEFAULT_FPS,
+            block_size: BlockSize::square(4),
+            modulation: Modulation::BINARY,
+            data_shards: 10,
+            parity_shards: 2,
+        };
+        let header = FileHeader::new("f".to_string(), 1, "PENDING".to_string(), &params);
+        assert!(FileTrailer::from_bytes(&header.to_bytes().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_frame_header_strip_recovery() {
+        let payload = vec![0xA5u8; 64];
+        let header = FrameHeader::new(42, 3, 11, &payload);
+
+        let bytes = header.to_bytes().unwrap();
+        assert_eq!(bytes.len(), FRAME_HEADER_SIZE);
+        assert_eq!(FrameHeader::from_bytes(&bytes).unwrap(), header);
+        assert!(header.verify(&payload));
+
+        let mut flipped = payload.clone();
+        flipped[17] ^= 0x04;
+        assert!(!header.verify(&flipped));
+
+        // One damaged copy is outvoted by the other two
+        let mut strip = bytes.repeat(FRAME_HEADER_COPIES);
+        strip[5] ^= 0xFF;
+        assert_eq!(FrameHeader::from_strip(&strip), Some(header));
+
+        // Damage in every copy: the CRC must reject it
+        let mut strip = bytes.repeat(FRAME_HEADER_COPIES);
+        for copy in 0..FRAME_HEADER_COPIES {
+            strip[copy * FRAME_HEADER_SIZE + 2] ^= 0x10;
+        }
+        assert_eq!(FrameHeader::from_strip(&strip), None);
+
+        // Flat bands read as 0xFF followed by zeros, a valid plain CRC32 message
+        let mut flat = vec![0xFFu8; 4];
+        flat.resize(FRAME_HEADER_SIZE - 4, 0);
+        flat.extend([0xFF; 4]);
+        assert!(FrameHeader::from_bytes(&flat).is_err());
     }
 }
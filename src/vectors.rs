@@ -0,0 +1,120 @@
+//! Conformance vectors: known inputs and the raw frames the encoder must
+//! render for them, bit for bit. The frames are checked in under
+//! `tests/vectors` for third-party decoders to test against; the format they
+//! follow is described in `docs/guide/format.md`.
+
+use crate::encoder::{EncodeOptions, Encoder};
+use crate::layout::BlockSize;
+use crate::modulation::Modulation;
+use crate::utils::{EncodingParams, FileHeader, FileTrailer, FrameHeader, Geometry, DEFAULT_FPS};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+/// Set to rewrite the checked-in frames after an intentional format change.
+pub const UPDATE_ENV: &str = "UPDATE_VECTORS";
+
+pub struct Vector {
+    pub name: &'static str,
+    pub params: EncodingParams,
+    /// `None` for bootstrap (header/trailer) frames
+    pub frame_header: Option<FrameHeader>,
+    /// What the frame carries: a shard, or the header/trailer bytes
+    pub data: Vec<u8>,
+}
+
+impl Vector {
+    pub fn path(&self) -> PathBuf {
+        dir().join(format!("{}.rgb", self.name))
+    }
+
+    /// The expected RGB24 frame.
+    pub fn frame(&self) -> Vec<u8> {
+        std::fs::read(self.path()).unwrap_or_else(|e| panic!("Failed to read {}: {}", self.path().display(), e))
+    }
+}
+
+fn dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/vectors")
+}
+
+/// 480x270, 4px blocks, binary, 2 data + 1 parity shards.
+fn binary_params() -> EncodingParams {
+    EncodingParams {
+        geometry: Geometry::new(480, 270),
+        fps: DEFAULT_FPS,
+        block_size: BlockSize::square(4),
+        modulation: Modulation::BINARY,
+        data_shards: 2,
+        parity_shards: 1,
+    }
+}
+
+/// Same geometry, 5x3 blocks with partial edge cells, 2 bits per channel in colour.
+fn colour_params() -> EncodingParams {
+    EncodingParams {
+        block_size: BlockSize { width: 5, height: 3 },
+        modulation: Modulation::new(2, true).unwrap(),
+        ..binary_params()
+    }
+}
+
+/// Every frame of encoding `input.bin` with the binary parameters (it fits
+/// in one chunk), apart from the repeated header, plus the first data frame
+/// of the same input with the colour parameters.
+pub fn all() -> Vec<Vector> {
+    let input = std::fs::read(dir().join("input.bin")).expect("Failed to read input.bin");
+    let hash = format!("{:x}", Sha256::digest(&input));
+    let size = input.len() as u64;
+
+    let params = binary_params();
+    assert!(input.len() <= params.chunk_size(), "input.bin must fit in one chunk");
+    let header = FileHeader::new("input.bin".to_string(), size, "PENDING".to_string(), &params);
+    let final_header = FileHeader { sha256_hash: hash.clone(), ..header.clone() };
+    let trailer = FileTrailer::new(hash, 1, size);
+
+    let mut vectors = vec![Vector {
+        name: "header",
+        params,
+        frame_header: None,
+        data: header.to_bytes().unwrap(),
+    }];
+    let shards = chunk_shards(params, &input);
+    for (name, (index, shard)) in ["data-0", "data-1", "parity-2"].into_iter().zip(shards.into_iter().enumerate()) {
+        vectors.push(Vector {
+            name,
+            params,
+            frame_header: Some(FrameHeader::new(index as u32 + 1, 0, index as u16, &shard)),
+            data: shard,
+        });
+    }
+    vectors.push(Vector {
+        name: "final-header",
+        params,
+        frame_header: None,
+        data: final_header.to_bytes().unwrap(),
+    });
+    vectors.push(Vector {
+        name: "trailer",
+        params,
+        frame_header: None,
+        data: trailer.to_bytes().unwrap(),
+    });
+
+    let params = colour_params();
+    let shard = chunk_shards(params, &input).swap_remove(0);
+    vectors.push(Vector {
+        name: "colour-data-0",
+        params,
+        frame_header: Some(FrameHeader::new(1, 0, 0, &shard)),
+        data: shard,
+    });
+    vectors
+}
+
+fn chunk_shards(params: EncodingParams, chunk: &[u8]) -> Vec<Vec<u8>> {
+    let rs = ReedSolomon::new(params.data_shards, params.parity_shards).unwrap();
+    Encoder::new(Vec::new(), String::new(), params, EncodeOptions::default())
+        .encode_chunk(&rs, chunk)
+        .unwrap()
+}